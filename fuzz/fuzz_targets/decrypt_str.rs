@@ -0,0 +1,14 @@
+#![no_main]
+
+use finance::util::prelude::{decode_digits, decrypt_str};
+use libfuzzer_sys::fuzz_target;
+
+// The `info` cookie is fully controlled by the client, decoding it must never panic.
+// The bytes are split before they are read as text, a split in the middle of a char isn't a finding.
+fuzz_target!(|data: &[u8]| {
+    let (info, password) = data.split_at(data.len() / 2);
+    if let (Ok(info), Ok(password)) = (std::str::from_utf8(info), std::str::from_utf8(password)) {
+        let _ = decode_digits(info);
+        let _ = decrypt_str(info, password);
+    }
+});
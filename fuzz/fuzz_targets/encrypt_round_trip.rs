@@ -0,0 +1,10 @@
+#![no_main]
+
+use finance::util::prelude::{decrypt_str, encrypt_str};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|input: (String, String)| {
+    let (message, password) = input;
+    let encrypted_data = encrypt_str(&message, &password).unwrap();
+    assert_eq!(decrypt_str(&encrypted_data, &password), Some(message));
+});
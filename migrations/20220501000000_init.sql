@@ -0,0 +1,13 @@
+-- The schema the server has always run on, `if not exists` keeps existing deployments untouched.
+create table if not exists user (
+    uid bigint not null primary key,
+    psd varchar(255) not null,
+    email varchar(255) not null
+);
+
+create table if not exists record (
+    uid bigint not null,
+    rid bigint not null,
+    details json not null,
+    primary key (uid, rid)
+);
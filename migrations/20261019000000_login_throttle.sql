@@ -0,0 +1,8 @@
+-- Login throttle shared by every instance, used when `rate_limit.store = "database"`.
+create table if not exists login_throttle (
+    `key` varchar(128) not null primary key,
+    tokens double not null,
+    updated_at bigint not null,
+    failures int unsigned not null,
+    locked_until bigint not null
+);
@@ -0,0 +1,3 @@
+alter table user add column verified boolean not null default false;
+-- The accounts made before the verification existed are trusted as they are.
+update user set verified = true;
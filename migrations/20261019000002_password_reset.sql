@@ -0,0 +1,9 @@
+-- The login cookies issued before this time are refused, e.g. after a password reset.
+alter table user add column sessions_valid_after bigint not null default 0;
+
+create table if not exists password_reset(
+    token_hash char(64) primary key,
+    uid bigint not null,
+    expires_at bigint not null,
+    index (uid)
+);
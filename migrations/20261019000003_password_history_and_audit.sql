@@ -0,0 +1,17 @@
+create table if not exists password_history(
+    id bigint auto_increment primary key,
+    uid bigint not null,
+    psd_hash char(64) not null,
+    changed_at bigint not null,
+    index (uid, id)
+);
+
+create table if not exists audit_event(
+    id bigint auto_increment primary key,
+    uid bigint not null,
+    action varchar(64) not null,
+    ip varchar(64) not null,
+    details json not null,
+    created_at bigint not null,
+    index (uid, created_at)
+);
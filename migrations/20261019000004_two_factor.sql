@@ -0,0 +1,18 @@
+alter table user
+    add column totp_secret varchar(64) null,
+    add column totp_enabled boolean not null default false,
+    -- The last time step a code was accepted for, so that every code is used once.
+    add column totp_last_step bigint not null default 0;
+
+create table if not exists recovery_code(
+    uid bigint not null,
+    code_hash char(64) not null,
+    primary key (uid, code_hash)
+);
+
+create table if not exists login_challenge(
+    token_hash char(64) primary key,
+    uid bigint not null,
+    expires_at bigint not null,
+    attempts int unsigned not null default 0
+);
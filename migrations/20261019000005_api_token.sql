@@ -0,0 +1,14 @@
+create table if not exists api_token(
+    id bigint auto_increment primary key,
+    uid bigint not null,
+    name varchar(64) not null,
+    -- Only the sha256 is kept, the token itself is shown once on creation.
+    token_hash char(64) not null unique,
+    -- read or read-write
+    scope varchar(16) not null,
+    created_at bigint not null,
+    expires_at bigint null,
+    last_used_at bigint null,
+    revoked_at bigint null,
+    index (uid)
+);
@@ -0,0 +1,14 @@
+-- The server assigns the uids from now on, above every uid taken and every uid of 5 to 12 digits
+-- the old clients could choose, so that a chosen uid never runs into the assigned ones.
+-- The start is kept in uid_range, the server tells the assigned uids apart by it.
+create table uid_range (first_assigned bigint not null);
+insert into uid_range select greatest(coalesce(max(uid), 0) + 1, 1000000000000) from user;
+alter table user modify uid bigint not null auto_increment;
+set @alter_uid = (select concat('alter table user auto_increment = ', first_assigned) from uid_range);
+prepare alter_uid from @alter_uid;
+execute alter_uid;
+deallocate prepare alter_uid;
+
+-- Users may log in by a username or by the email as well.
+alter table user add column username varchar(64) null unique;
+create index user_email on user (email);
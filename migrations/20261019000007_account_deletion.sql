@@ -0,0 +1,3 @@
+-- When a deleted account is purged, null while it isn't deleted.
+alter table user add column delete_after bigint null;
+create index user_delete_after on user (delete_after);
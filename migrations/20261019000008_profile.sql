@@ -0,0 +1,11 @@
+-- The profile and preferences of the user, synced by the clients through `/profile`.
+alter table user
+    add column display_name varchar(64) not null default '',
+    add column base_currency char(3) not null default 'CNY',
+    add column timezone varchar(64) not null default 'Asia/Shanghai',
+    add column locale varchar(35) not null default 'zh-CN',
+    -- 1 is monday and 7 is sunday, as iso 8601.
+    add column first_day_of_week tinyint unsigned not null default 1,
+    -- The day the fiscal month starts on, up to 28 so that every month has it.
+    add column month_start_day tinyint unsigned not null default 1,
+    add column profile_updated_at bigint not null default 0;
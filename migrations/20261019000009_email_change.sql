@@ -0,0 +1,8 @@
+-- Email changes waiting for the confirmation mailed to the new address.
+create table if not exists email_change(
+    token_hash char(64) not null primary key,
+    uid bigint not null,
+    new_email varchar(255) not null,
+    expires_at bigint not null,
+    index (uid)
+);
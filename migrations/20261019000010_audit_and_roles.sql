@@ -0,0 +1,6 @@
+-- Audit events are only ever inserted, and deleted by age or with the account.
+alter table audit_event add column user_agent varchar(255) not null default '';
+create index audit_event_created_at on audit_event (created_at);
+
+-- user or admin, see the admin endpoints.
+alter table user add column role varchar(16) not null default 'user';
@@ -0,0 +1,4 @@
+-- Set by an admin, a disabled account can't log in and its sessions and tokens are refused.
+alter table user add column disabled_at bigint null;
+-- Set by an admin, the login is refused until the password is reset by the mailed link.
+alter table user add column password_reset_required boolean not null default false;
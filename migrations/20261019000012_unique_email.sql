@@ -0,0 +1,8 @@
+-- One account per email, held by the database so that two registers at once can't both take it.
+-- The accounts which shared an email before keep it, all but the first are told apart by their uid
+-- in email_dup, and none of them logs in by it. A confirmed email change clears it.
+alter table user add column email_dup bigint not null default 0;
+update user join (select email, min(uid) as first from user group by email) as firsts
+    on user.email = firsts.email and user.uid <> firsts.first
+set user.email_dup = user.uid;
+create unique index user_email_unique on user (email, email_dup);
@@ -0,0 +1,109 @@
+-- The schema of ./migrations as it stands, for restoring a backup into SQLite.
+-- Keep it in step with every migration which changes a table.
+create table if not exists user (
+    uid integer not null primary key,
+    psd text not null,
+    email text not null,
+    verified integer not null default 0,
+    sessions_valid_after integer not null default 0,
+    totp_secret text null,
+    totp_enabled integer not null default 0,
+    totp_last_step integer not null default 0,
+    username text null unique,
+    delete_after integer null,
+    display_name text not null default '',
+    base_currency text not null default 'CNY',
+    timezone text not null default 'Asia/Shanghai',
+    locale text not null default 'zh-CN',
+    first_day_of_week integer not null default 1,
+    month_start_day integer not null default 1,
+    profile_updated_at integer not null default 0,
+    role text not null default 'user',
+    disabled_at integer null,
+    password_reset_required integer not null default 0,
+    email_dup integer not null default 0
+);
+create index if not exists user_email on user (email);
+create unique index if not exists user_email_unique on user (email, email_dup);
+create index if not exists user_delete_after on user (delete_after);
+
+create table if not exists uid_range (
+    first_assigned integer not null
+);
+insert into uid_range select 1000000000000 where not exists (select 1 from uid_range);
+
+create table if not exists record (
+    uid integer not null,
+    rid integer not null,
+    details text not null,
+    primary key (uid, rid)
+);
+
+create table if not exists login_throttle (
+    `key` text not null primary key,
+    tokens real not null,
+    updated_at integer not null,
+    failures integer not null,
+    locked_until integer not null
+);
+
+create table if not exists password_reset (
+    token_hash text not null primary key,
+    uid integer not null,
+    expires_at integer not null
+);
+create index if not exists password_reset_uid on password_reset (uid);
+
+create table if not exists password_history (
+    id integer primary key autoincrement,
+    uid integer not null,
+    psd_hash text not null,
+    changed_at integer not null
+);
+create index if not exists password_history_uid on password_history (uid, id);
+
+create table if not exists audit_event (
+    id integer primary key autoincrement,
+    uid integer not null,
+    action text not null,
+    ip text not null,
+    details text not null,
+    created_at integer not null,
+    user_agent text not null default ''
+);
+create index if not exists audit_event_uid on audit_event (uid, created_at);
+create index if not exists audit_event_created_at on audit_event (created_at);
+
+create table if not exists recovery_code (
+    uid integer not null,
+    code_hash text not null,
+    primary key (uid, code_hash)
+);
+
+create table if not exists login_challenge (
+    token_hash text not null primary key,
+    uid integer not null,
+    expires_at integer not null,
+    attempts integer not null default 0
+);
+
+create table if not exists api_token (
+    id integer primary key autoincrement,
+    uid integer not null,
+    name text not null,
+    token_hash text not null unique,
+    scope text not null,
+    created_at integer not null,
+    expires_at integer null,
+    last_used_at integer null,
+    revoked_at integer null
+);
+create index if not exists api_token_uid on api_token (uid);
+
+create table if not exists email_change (
+    token_hash text not null primary key,
+    uid integer not null,
+    new_email text not null,
+    expires_at integer not null
+);
+create index if not exists email_change_uid on email_change (uid);
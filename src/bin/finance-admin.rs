@@ -0,0 +1,516 @@
+// The operator's tool, on the same config and database as the server.
+// Run `finance-admin help` for the commands.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::{env, fs, process};
+
+use chrono::Utc;
+use serde_json::{json, Value as Json};
+use sqlx::mysql::MySqlPoolOptions;
+use sqlx::{MySqlConnection, MySqlPool, Row};
+
+use finance::config::Config;
+use finance::route::prelude::{
+    email_name, find_uid, insert_records, revoke_api_tokens, uid_allowed, user_usage, Record,
+};
+use finance::util::prelude::{
+    first_assigned_uid, insert_audit_event, is_duplicate_key, match_email, migrate,
+    pending_migrations, prune_audit_events, purge_deleted_accounts, random_token, read_archive,
+    remember_password, restore_backup, vacuum_expired, write_backup, ApiError, LoginNames,
+    Manifest, PasswordPolicy,
+};
+
+type CliResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+const USAGE: &str = "usage: finance-admin <command> [options]
+
+commands:
+  create-user --email EMAIL [--username NAME] [--uid UID] [--password PASSWORD] [--admin]
+  reset-password LOGIN [--password PASSWORD]
+  migrate [--status]
+  export LOGIN [--out FILE]
+  import LOGIN --file FILE
+  vacuum
+  stats
+  backup --out FILE
+  verify FILE
+  restore FILE [--to DATABASE_URL]
+
+LOGIN is a uid, username or email, as `[account] login_names` accepts.
+A password left out is generated and printed.
+A backup is restored into an empty MySQL or SQLite database, the configured one by default.
+The config is read from finance.toml, or from the file FINANCE_CONFIG names.";
+
+// The options which take no value.
+const FLAGS: &[&str] = &["admin", "status"];
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Args {
+    command: String,
+    positional: Vec<String>,
+    options: HashMap<String, String>,
+}
+
+impl Args {
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let mut parsed = Args::default();
+        let mut args = args.iter();
+        parsed.command = args.next().cloned().unwrap_or_default();
+        while let Some(arg) = args.next() {
+            match arg.strip_prefix("--") {
+                Some(name) if FLAGS.contains(&name) => {
+                    parsed.options.insert(name.to_string(), String::new());
+                }
+                Some(name) => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| format!("--{} needs a value", name))?;
+                    parsed.options.insert(name.to_string(), value.clone());
+                }
+                None => parsed.positional.push(arg.clone()),
+            }
+        }
+        Ok(parsed)
+    }
+
+    fn option(&self, name: &str) -> Option<&str> {
+        self.options.get(name).map(String::as_str)
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        self.options.contains_key(name)
+    }
+
+    fn login(&self) -> CliResult<&str> {
+        Ok(self
+            .positional
+            .first()
+            .ok_or("a uid, username or email is needed")?)
+    }
+}
+
+// Who ran the command, recorded with the events it audits.
+fn operator() -> String {
+    env::var("USER").unwrap_or_default()
+}
+
+async fn audit_cli(
+    conn: &mut MySqlConnection,
+    uid: i64,
+    action: &str,
+    mut details: Json,
+) -> CliResult<()> {
+    details["operator"] = json!(operator());
+    insert_audit_event(conn, uid, action, "", "finance-admin", details).await?;
+    Ok(())
+}
+
+// The login names of the config, with the uids assigned by the server told apart.
+async fn login_names(pool: &MySqlPool, config: &Config) -> CliResult<LoginNames> {
+    let mut conn = pool.acquire().await?;
+    let first_assigned = first_assigned_uid(&mut conn).await?;
+    Ok(LoginNames::new(&config.account)?.assigned_from(first_assigned))
+}
+
+async fn resolve_uid(pool: &MySqlPool, names: &LoginNames, login: &str) -> CliResult<i64> {
+    let name = names
+        .parse(login)
+        .ok_or_else(|| format!("{} isn't a uid, username or email", login))?;
+    let mut conn = pool.acquire().await?;
+    Ok(find_uid(&mut conn, &name)
+        .await?
+        .ok_or_else(|| format!("no account is named {}", login))?)
+}
+
+// The password given, held to the policy, or a generated one.
+fn choose_password(
+    policy: &PasswordPolicy,
+    given: Option<&str>,
+    user_inputs: &[&str],
+) -> CliResult<String> {
+    match given {
+        Some(password) => {
+            let reasons = policy.reasons(password, user_inputs);
+            if !reasons.is_empty() {
+                return Err(format!("the password is refused: {}", reasons.join(", ")).into());
+            }
+            Ok(password.to_string())
+        }
+        None => {
+            let password = random_token(12);
+            println!("password: {}", password);
+            Ok(password)
+        }
+    }
+}
+
+// An account made by the operator is verified, the address is trusted.
+async fn create_user(pool: &MySqlPool, config: &Config, args: &Args) -> CliResult<()> {
+    let names = login_names(pool, config).await?;
+    let email = args.option("email").unwrap_or_default();
+    let username = args.option("username").unwrap_or_default();
+    if !match_email(email) {
+        return Err("--email isn't an email".into());
+    }
+    if !username.is_empty() && !names.match_username(username) {
+        return Err("--username doesn't match `[account] username_format`".into());
+    }
+    // Held to `[account] uid_policy` as on register, a chosen uid must not move the counter.
+    let uid = match args.option("uid").map(str::parse::<i64>) {
+        Some(Ok(uid)) if uid != 0 && uid_allowed(&config.account.uid_policy, &names, uid) => {
+            Some(uid)
+        }
+        Some(_) => return Err(ApiError::IncorrectUidFormat.into()),
+        None if uid_allowed(&config.account.uid_policy, &names, 0) => None,
+        None => return Err(ApiError::IncorrectUidFormat.into()),
+    };
+    let policy = PasswordPolicy::new(&config.password)?;
+    let password = choose_password(
+        &policy,
+        args.option("password"),
+        &[username, email_name(email)],
+    )?;
+    let role = if args.flag("admin") { "admin" } else { "user" };
+
+    let mut tx = pool.begin().await?;
+    let inserted = sqlx::query(
+        "insert into user(uid, username, psd, email, verified, role) values(?, ?, ?, ?, true, ?)",
+    )
+    .bind(uid)
+    .bind(Some(username).filter(|it| !it.is_empty()))
+    .bind(&password)
+    .bind(email)
+    .bind(role)
+    .execute(&mut tx)
+    .await
+    .map_err(|e| {
+        if is_duplicate_key(&e) {
+            "the uid, username or email is in use by another account".to_string()
+        } else {
+            format!("the account can't be created: {}", e)
+        }
+    })?;
+    let uid = uid.unwrap_or(inserted.last_insert_id() as i64);
+    remember_password(&mut tx, uid, &password, config.password.history).await?;
+    audit_cli(&mut tx, uid, "admin.user.create", json!({ "role": role })).await?;
+    tx.commit().await?;
+
+    println!("uid: {}", uid);
+    Ok(())
+}
+
+// Set the password at once, every session of the user is logged out.
+async fn reset_password(pool: &MySqlPool, config: &Config, args: &Args) -> CliResult<()> {
+    let names = login_names(pool, config).await?;
+    let uid = resolve_uid(pool, &names, args.login()?).await?;
+    let mut tx = pool.begin().await?;
+    let row = sqlx::query("select email, username from user where uid=?")
+        .bind(uid)
+        .fetch_one(&mut tx)
+        .await?;
+    let email = row.get::<String, &str>("email");
+    let username = row
+        .get::<Option<String>, &str>("username")
+        .unwrap_or_default();
+    let policy = PasswordPolicy::new(&config.password)?;
+    let password = choose_password(
+        &policy,
+        args.option("password"),
+        &[&username, email_name(&email)],
+    )?;
+
+    let now = Utc::now().timestamp();
+    sqlx::query(
+        "update user set psd=?, sessions_valid_after=?, password_reset_required=false where uid=?",
+    )
+    .bind(&password)
+    .bind(now)
+    .bind(uid)
+    .execute(&mut tx)
+    .await?;
+    let tokens = revoke_api_tokens(&mut tx, uid, now).await?;
+    remember_password(&mut tx, uid, &password, config.password.history).await?;
+    audit_cli(
+        &mut tx,
+        uid,
+        "admin.user.password_set",
+        json!({ "tokens": tokens }),
+    )
+    .await?;
+    tx.commit().await?;
+
+    println!("the password of {} is reset", uid);
+    Ok(())
+}
+
+async fn run_migrations(pool: &MySqlPool, args: &Args) -> CliResult<()> {
+    if !args.flag("status") {
+        migrate(pool).await?;
+    }
+    let pending = pending_migrations(pool).await?;
+    if pending.is_empty() {
+        println!("the database is up to date");
+    }
+    for version in pending {
+        println!("pending: {}", version);
+    }
+    Ok(())
+}
+
+// The records as `/download` sends them, by rid.
+async fn export_ledger(pool: &MySqlPool, config: &Config, args: &Args) -> CliResult<()> {
+    let names = login_names(pool, config).await?;
+    let uid = resolve_uid(pool, &names, args.login()?).await?;
+    let mut conn = pool.acquire().await?;
+    let records: Vec<Json> = sqlx::query("select details from record where uid=? order by rid")
+        .bind(uid)
+        .fetch_all(&mut conn)
+        .await?
+        .into_iter()
+        .map(|row| row.get::<Json, usize>(0))
+        .collect();
+    let text = serde_json::to_string_pretty(&records)?;
+    match args.option("out") {
+        Some(path) => {
+            fs::write(path, text)?;
+            eprintln!("{} records of {} written to {}", records.len(), uid, path);
+        }
+        None => println!("{}", text),
+    }
+    audit_cli(
+        &mut conn,
+        uid,
+        "admin.records.export",
+        json!({ "records": records.len() }),
+    )
+    .await?;
+    Ok(())
+}
+
+// Append the records of an export, or of any `/upload` body, after the ones of the user.
+// Nothing is imported unless every record is.
+async fn import_ledger(pool: &MySqlPool, config: &Config, args: &Args) -> CliResult<()> {
+    let names = login_names(pool, config).await?;
+    let uid = resolve_uid(pool, &names, args.login()?).await?;
+    let path = args.option("file").ok_or("--file is needed")?;
+    let records: Vec<Record> = serde_json::from_str(&fs::read_to_string(path)?)
+        .map_err(|e| format!("{} isn't a list of records: {}", path, e))?;
+    let count = records.len();
+
+    let mut tx = pool.begin().await?;
+    let failed = insert_records(&mut tx, uid, records).await?;
+    if failed > 0 {
+        return Err(format!(
+            "{} of {} records failed, nothing is imported",
+            failed, count
+        )
+        .into());
+    }
+    audit_cli(
+        &mut tx,
+        uid,
+        "admin.records.import",
+        json!({ "records": count }),
+    )
+    .await?;
+    tx.commit().await?;
+
+    println!("{} records imported to {}", count, uid);
+    Ok(())
+}
+
+// What the background maintenance does, and the rows which can't be used anymore.
+async fn vacuum(pool: &MySqlPool, config: &Config) -> CliResult<()> {
+    println!("accounts purged: {}", purge_deleted_accounts(pool).await?);
+    println!(
+        "audit events pruned: {}",
+        prune_audit_events(pool, config.audit.retention_days).await?
+    );
+    for (table, count) in vacuum_expired(pool).await? {
+        println!("{} deleted: {}", table, count);
+    }
+    Ok(())
+}
+
+async fn stats(pool: &MySqlPool) -> CliResult<()> {
+    let users = sqlx::query(
+        "select count(*) as users, cast(coalesce(sum(verified), 0) as signed) as verified, cast(coalesce(sum(disabled_at is not null), 0) as signed) as disabled, cast(coalesce(sum(role='admin'), 0) as signed) as admins, cast(coalesce(sum(delete_after is not null), 0) as signed) as deleting from user",
+    )
+    .fetch_one(pool)
+    .await?;
+    let records = sqlx::query(
+        "select count(*) as records, cast(coalesce(sum(json_storage_size(details)), 0) as signed) as bytes from record",
+    )
+    .fetch_one(pool)
+    .await?;
+    let tokens = sqlx::query(
+        "select count(*) from api_token where revoked_at is null and (expires_at is null or expires_at>?)",
+    )
+    .bind(Utc::now().timestamp())
+    .fetch_one(pool)
+    .await?;
+    let events = sqlx::query("select count(*) from audit_event")
+        .fetch_one(pool)
+        .await?;
+    let lines = [
+        ("users", users.get::<i64, &str>("users")),
+        ("verified", users.get::<i64, &str>("verified")),
+        ("disabled", users.get::<i64, &str>("disabled")),
+        ("admins", users.get::<i64, &str>("admins")),
+        ("pending deletion", users.get::<i64, &str>("deleting")),
+        ("records", records.get::<i64, &str>("records")),
+        ("storage bytes", records.get::<i64, &str>("bytes")),
+        ("api tokens", tokens.get::<i64, usize>(0)),
+        ("audit events", events.get::<i64, usize>(0)),
+        (
+            "pending migrations",
+            pending_migrations(pool).await?.len() as i64,
+        ),
+    ];
+    for (name, value) in lines {
+        println!("{:<20}{}", name, value);
+    }
+
+    // The largest ledgers, which a quota would hit first.
+    let largest: Vec<i64> = sqlx::query(
+        "select uid from record group by uid order by sum(json_storage_size(details)) desc limit 5",
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|row| row.get::<i64, &str>("uid"))
+    .collect();
+    let mut conn = pool.acquire().await?;
+    for uid in largest {
+        let usage = user_usage(&mut conn, uid).await?;
+        println!(
+            "uid {:<14}{} records, {} bytes",
+            uid, usage["records"], usage["storage_bytes"]
+        );
+    }
+    Ok(())
+}
+
+fn print_tables(manifest: &Manifest) {
+    for table in &manifest.tables {
+        println!(
+            "{:<20}{} rows  sha256 {}",
+            table.name, table.rows, table.sha256
+        );
+    }
+}
+
+async fn backup(pool: &MySqlPool, args: &Args) -> CliResult<()> {
+    let path = args.option("out").ok_or("--out is needed")?;
+    // One transaction, so that every table is read at the same point in time.
+    let mut tx = pool.begin().await?;
+    let manifest = write_backup(&mut tx, File::create(path)?).await?;
+    let rows: u64 = manifest.tables.iter().map(|it| it.rows).sum();
+    audit_cli(&mut tx, 0, "admin.backup", json!({ "rows": rows })).await?;
+    tx.commit().await?;
+    print_tables(&manifest);
+    println!("backup written to {}", path);
+    Ok(())
+}
+
+// Check every checksum and row of the archive, without a database.
+fn verify(args: &Args) -> CliResult<()> {
+    let path = args.positional.first().ok_or("the archive is needed")?;
+    let (manifest, _) = read_archive(File::open(path)?)?;
+    print_tables(&manifest);
+    println!(
+        "{} is intact, format {} version {}, schema {}",
+        path, manifest.format, manifest.version, manifest.schema_version
+    );
+    Ok(())
+}
+
+async fn restore(config: &Config, args: &Args) -> CliResult<()> {
+    let path = args.positional.first().ok_or("the archive is needed")?;
+    let url = args.option("to").unwrap_or(&config.database_url);
+    let manifest = restore_backup(File::open(path)?, url).await?;
+    print_tables(&manifest);
+    println!("{} restored", path);
+    Ok(())
+}
+
+async fn run(args: Args) -> CliResult<()> {
+    if args.command.is_empty() || args.command == "help" {
+        println!("{}", USAGE);
+        return Ok(());
+    }
+    if args.command == "verify" {
+        return verify(&args);
+    }
+    let config = Config::load()?;
+    // The target may be another database, or SQLite, which the pool can't connect to.
+    if args.command == "restore" {
+        return restore(&config, &args).await;
+    }
+    let pool = MySqlPoolOptions::new()
+        .max_connections(2)
+        .connect(&config.database_url)
+        .await?;
+    let res = match args.command.as_str() {
+        "create-user" => create_user(&pool, &config, &args).await,
+        "reset-password" => reset_password(&pool, &config, &args).await,
+        "migrate" => run_migrations(&pool, &args).await,
+        "export" => export_ledger(&pool, &config, &args).await,
+        "import" => import_ledger(&pool, &config, &args).await,
+        "vacuum" => vacuum(&pool, &config).await,
+        "stats" => stats(&pool).await,
+        "backup" => backup(&pool, &args).await,
+        command => Err(format!("unknown command {}\n\n{}", command, USAGE).into()),
+    };
+    pool.close().await;
+    res
+}
+
+#[async_std::main]
+async fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let res = match Args::parse(&args) {
+        Ok(args) => run(args).await,
+        Err(e) => Err(e.into()),
+    };
+    if let Err(e) = res {
+        eprintln!("finance-admin: {}", e);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Args;
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        let args: Vec<String> = args.iter().map(|it| it.to_string()).collect();
+        Args::parse(&args)
+    }
+
+    #[test]
+    fn arguments_are_parsed() {
+        let args = parse(&[
+            "create-user",
+            "--email",
+            "a@b.com",
+            "--admin",
+            "--uid",
+            "100",
+        ])
+        .unwrap();
+        assert_eq!(args.command, "create-user");
+        assert_eq!(args.option("email"), Some("a@b.com"));
+        assert_eq!(args.option("uid"), Some("100"));
+        assert!(args.flag("admin") && !args.flag("status"));
+
+        let args = parse(&["export", "alice", "--out", "alice.json"]).unwrap();
+        assert_eq!(args.login().unwrap(), "alice");
+        assert_eq!(args.option("out"), Some("alice.json"));
+
+        assert!(parse(&["import", "alice", "--file"]).is_err());
+        assert_eq!(parse(&[]).unwrap(), Args::default());
+    }
+}
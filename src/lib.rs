@@ -0,0 +1,5 @@
+// The server and `finance-admin` share the config, the database layer and the routes.
+pub mod config;
+pub mod route;
+pub mod state;
+pub mod util;
@@ -0,0 +1,313 @@
+use std::io::{Cursor, Write};
+
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::{json, Value as Json};
+use sqlx::{Acquire, MySql, Row};
+use tide::{log, Body, Request, Response, StatusCode};
+use tide_sqlx::SQLxRequestExt;
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use super::api_token::revoke_api_tokens;
+use super::user::verify_password_throttled;
+use crate::state::State;
+use crate::util::prelude::*;
+
+#[derive(Deserialize)]
+struct DeleteBody {
+    #[serde(default, deserialize_with = "lenient")]
+    password: String,
+}
+
+impl Validate for DeleteBody {
+    fn validate(&self) -> Result<(), ApiError> {
+        Rules::new()
+            .check(
+                "password",
+                !self.password.is_empty(),
+                ApiError::IncorrectPasswordFormat,
+            )
+            .finish()
+    }
+}
+
+// A page of the audit log, see `audit_events`.
+#[derive(Deserialize)]
+#[serde(default)]
+struct ActivityQuery {
+    before: i64,
+    limit: u32,
+}
+
+impl Default for ActivityQuery {
+    fn default() -> Self {
+        Self {
+            before: 0,
+            limit: 50,
+        }
+    }
+}
+
+// A zip of the json files, in the order given.
+fn export_archive(files: &[(&str, Json)]) -> zip::result::ZipResult<Vec<u8>> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+    for (name, content) in files {
+        zip.start_file(*name, options)?;
+        zip.write_all(&serde_json::to_vec_pretty(content).unwrap_or_default())?;
+    }
+    Ok(zip.finish()?.into_inner())
+}
+
+// A login within the grace period keeps the account.
+pub async fn cancel_deletion(req: &Request<State>, uid: i64) -> Result<(), ApiError> {
+    let mut conn = req.sqlx_conn::<MySql>().await;
+    let cancelled =
+        sqlx::query("update user set delete_after=null where uid=? and delete_after is not null")
+            .bind(uid)
+            .execute(conn.acquire().await?)
+            .await?;
+    if cancelled.rows_affected() > 0 {
+        audit(
+            conn.acquire().await?,
+            req,
+            uid,
+            "account.delete.cancel",
+            json!({}),
+        )
+        .await?;
+        log::info!("account deletion cancelled", { uid: uid });
+    }
+    Ok(())
+}
+
+// Delete the account once the password is confirmed, at once or after `[account] deletion_grace_days`.
+pub async fn delete_account(mut req: Request<State>) -> Result<Response, ApiError> {
+    let uid = current_user(&req, Access::Session).await?;
+    let DeleteBody { password } = body(&mut req).await?;
+    verify_password_throttled(&req, uid, &password, None).await?;
+
+    let mut conn = req.sqlx_conn::<MySql>().await;
+    let email = sqlx::query("select email from user where uid=?")
+        .bind(uid)
+        .fetch_one(conn.acquire().await?)
+        .await?
+        .get::<String, &str>("email");
+
+    let grace_days = req.state().config.account.deletion_grace_days;
+    let now = Utc::now().timestamp();
+    let (delete_after, body) = if grace_days == 0 {
+        // The sessions and api tokens need the rows purged here, so nothing is left to revoke.
+        audit(
+            conn.acquire().await?,
+            &req,
+            uid,
+            "account.delete",
+            json!({ "delete_after": null }),
+        )
+        .await?;
+        purge_user(conn.acquire().await?, uid).await?;
+        log::info!("account deleted", { uid: uid });
+        (
+            None,
+            format!(
+                "Your finance account {} and all of its records were deleted.",
+                uid
+            ),
+        )
+    } else {
+        // Nothing may use the account until it's purged, unless a login cancels the deletion.
+        let delete_after = now + i64::from(grace_days) * 86400;
+        sqlx::query("update user set delete_after=?, sessions_valid_after=? where uid=?")
+            .bind(delete_after)
+            .bind(now)
+            .bind(uid)
+            .execute(conn.acquire().await?)
+            .await?;
+        revoke_api_tokens(conn.acquire().await?, uid, now).await?;
+        audit(
+            conn.acquire().await?,
+            &req,
+            uid,
+            "account.delete",
+            json!({ "delete_after": delete_after }),
+        )
+        .await?;
+        log::info!("account deletion scheduled", { uid: uid, delete_after: delete_after });
+        (
+            Some(delete_after),
+            format!(
+                "Your finance account {} will be deleted with all of its records in {} days.\n\nLog in before then to keep it.",
+                uid, grace_days
+            ),
+        )
+    };
+    let mail = Mail {
+        to: email,
+        subject: "Your account is deleted".to_string(),
+        body,
+    };
+    send_in_background(req.state().mailer.clone(), mail);
+
+    Ok(succeed(json!([{ "delete_after": delete_after }])))
+}
+
+// The audit events of the user, newest first.
+pub async fn account_activity(req: Request<State>) -> Result<Response, ApiError> {
+    let uid = current_user(&req, Access::Session).await?;
+    let query: ActivityQuery = req.query().unwrap_or_default();
+
+    let mut conn = req.sqlx_conn::<MySql>().await;
+    let events = audit_events(
+        conn.acquire().await?,
+        Some(uid),
+        query.before,
+        query.limit.clamp(1, 200),
+    )
+    .await?;
+
+    Ok(succeed(json!(events)))
+}
+
+// Everything kept about the user, as a zip of json files.
+pub async fn export_account(req: Request<State>) -> Result<Response, ApiError> {
+    let uid = current_user(&req, Access::Session).await?;
+
+    let mut conn = req.sqlx_conn::<MySql>().await;
+    let row = sqlx::query(
+        "select username, email, verified, totp_enabled, delete_after from user where uid=?",
+    )
+    .bind(uid)
+    .fetch_one(conn.acquire().await?)
+    .await?;
+    let profile = json!({
+        "uid": uid,
+        "username": row.get::<Option<String>, &str>("username"),
+        "email": row.get::<String, &str>("email"),
+        "verified": row.get::<bool, &str>("verified"),
+        "delete_after": row.get::<Option<i64>, &str>("delete_after"),
+    });
+
+    let records: Vec<Json> = sqlx::query("select details from record where uid=? order by rid")
+        .bind(uid)
+        .fetch_all(conn.acquire().await?)
+        .await?
+        .into_iter()
+        .map(|row| row.get::<Json, &str>("details"))
+        .collect();
+    // The categories are the record types in use, with how many records each.
+    let mut categories: Vec<(String, u64)> = vec![];
+    for record in &records {
+        let name = record["record_type"].as_str().unwrap_or_default();
+        match categories.iter_mut().find(|(it, _)| it == name) {
+            Some((_, count)) => *count += 1,
+            None => categories.push((name.to_string(), 1)),
+        }
+    }
+    let categories: Vec<Json> = categories
+        .into_iter()
+        .map(|(name, count)| json!({"name": name, "records": count}))
+        .collect();
+
+    let tokens: Vec<Json> = sqlx::query(
+        "select id, name, scope, created_at, expires_at, last_used_at, revoked_at from api_token where uid=? order by id",
+    )
+    .bind(uid)
+    .fetch_all(conn.acquire().await?)
+    .await?
+    .into_iter()
+    .map(|row| {
+        json!({
+            "id": row.get::<i64, &str>("id"),
+            "name": row.get::<String, &str>("name"),
+            "scope": row.get::<String, &str>("scope"),
+            "created_at": row.get::<i64, &str>("created_at"),
+            "expires_at": row.get::<Option<i64>, &str>("expires_at"),
+            "last_used_at": row.get::<Option<i64>, &str>("last_used_at"),
+            "revoked_at": row.get::<Option<i64>, &str>("revoked_at"),
+        })
+    })
+    .collect();
+    let preferences = Preferences::load(conn.acquire().await?, uid).await?;
+    let settings = json!({
+        "preferences": preferences,
+        "two_factor": row.get::<bool, &str>("totp_enabled"),
+        "api_tokens": tokens,
+    });
+
+    let activity: Vec<Json> = sqlx::query(
+        "select action, ip, details, created_at from audit_event where uid=? order by id",
+    )
+    .bind(uid)
+    .fetch_all(conn.acquire().await?)
+    .await?
+    .into_iter()
+    .map(|row| {
+        json!({
+            "action": row.get::<String, &str>("action"),
+            "ip": row.get::<String, &str>("ip"),
+            "details": row.get::<Json, &str>("details"),
+            "created_at": row.get::<i64, &str>("created_at"),
+        })
+    })
+    .collect();
+    audit(
+        conn.acquire().await?,
+        &req,
+        uid,
+        "account.export",
+        json!({ "records": records.len() }),
+    )
+    .await?;
+
+    let archive = export_archive(&[
+        ("profile.json", profile),
+        ("records.json", json!(records)),
+        ("categories.json", json!(categories)),
+        ("settings.json", settings),
+        ("activity.json", json!(activity)),
+    ])
+    .map_err(|_| ApiError::Internal)?;
+    log::info!("account exported", { uid: uid, bytes: archive.len() });
+
+    let filename = format!("finance-export-{}-{}.zip", uid, Utc::now().format("%Y%m%d"));
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_bytes(archive))
+        .content_type("application/zip")
+        .header(
+            "Content-Disposition",
+            format!("attachment; filename=\"{}\"", filename),
+        )
+        .build())
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Cursor, Read};
+
+    use serde_json::json;
+    use zip::ZipArchive;
+
+    use crate::route::account::export_archive;
+
+    #[test]
+    fn archive_holds_every_file() {
+        let archive = export_archive(&[
+            ("profile.json", json!({"uid": 12345})),
+            ("records.json", json!([])),
+        ])
+        .unwrap();
+        let mut zip = ZipArchive::new(Cursor::new(archive)).unwrap();
+        assert_eq!(zip.len(), 2);
+        let mut profile = String::new();
+        zip.by_name("profile.json")
+            .unwrap()
+            .read_to_string(&mut profile)
+            .unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&profile).unwrap(),
+            json!({"uid": 12345})
+        );
+    }
+}
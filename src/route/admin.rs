@@ -0,0 +1,415 @@
+use std::io::Cursor;
+
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::{json, Value as Json};
+use sqlx::mysql::MySqlRow;
+use sqlx::{Acquire, MySql, MySqlConnection, Row};
+use tide::{log, Body, Request, Response, StatusCode};
+use tide_sqlx::SQLxRequestExt;
+
+use super::api_token::revoke_api_tokens;
+use super::password_reset::send_reset_link;
+use crate::state::State;
+use crate::util::prelude::*;
+
+const USER_COLUMNS: &str = "uid, username, email, role, verified, display_name, disabled_at, delete_after, password_reset_required";
+
+// A page of the audit log of everyone, or of the user given.
+#[derive(Deserialize)]
+#[serde(default)]
+struct ActivityQuery {
+    uid: Option<i64>,
+    before: i64,
+    limit: u32,
+}
+
+impl Default for ActivityQuery {
+    fn default() -> Self {
+        Self {
+            uid: None,
+            before: 0,
+            limit: 50,
+        }
+    }
+}
+
+// A page of the users, by uid ascending.
+// `q` is a uid or a part of a username or email, `after` is the last uid of the previous page.
+#[derive(Deserialize)]
+#[serde(default)]
+struct UsersQuery {
+    q: String,
+    role: String,
+    disabled: Option<bool>,
+    after: i64,
+    limit: u32,
+}
+
+impl Default for UsersQuery {
+    fn default() -> Self {
+        Self {
+            q: String::new(),
+            role: String::new(),
+            disabled: None,
+            after: 0,
+            limit: 50,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RoleBody {
+    #[serde(default, deserialize_with = "lenient")]
+    role: String,
+}
+
+impl Validate for RoleBody {
+    fn validate(&self) -> Result<(), ApiError> {
+        Rules::new()
+            .check(
+                "role",
+                self.role == "user" || self.role == "admin",
+                ApiError::IncorrectRole,
+            )
+            .finish()
+    }
+}
+
+fn user_json(row: &MySqlRow) -> Json {
+    json!({
+        "uid": row.get::<i64, &str>("uid"),
+        "username": row.get::<Option<String>, &str>("username"),
+        "email": row.get::<String, &str>("email"),
+        "role": row.get::<String, &str>("role"),
+        "verified": row.get::<bool, &str>("verified"),
+        "display_name": row.get::<String, &str>("display_name"),
+        "disabled_at": row.get::<Option<i64>, &str>("disabled_at"),
+        "delete_after": row.get::<Option<i64>, &str>("delete_after"),
+        "password_reset_required": row.get::<bool, &str>("password_reset_required"),
+    })
+}
+
+// The text of `like`, matching `text` anywhere.
+fn like_pattern(text: &str) -> String {
+    let escaped = text
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{}%", escaped)
+}
+
+async fn load_user(conn: &mut MySqlConnection, uid: i64) -> Result<Json, ApiError> {
+    let row = sqlx::query(&format!("select {} from user where uid=?", USER_COLUMNS))
+        .bind(uid)
+        .fetch_optional(conn)
+        .await?
+        .ok_or(ApiError::UserNotExists)?;
+    Ok(user_json(&row))
+}
+
+// The uid of `/admin/users/:uid`, unknown ones are refused as `UserNotExists`.
+async fn target_user(req: &Request<State>, conn: &mut MySqlConnection) -> Result<i64, ApiError> {
+    let uid = req
+        .param("uid")
+        .ok()
+        .and_then(|it| it.parse::<i64>().ok())
+        .unwrap_or_default();
+    sqlx::query("select uid from user where uid=?")
+        .bind(uid)
+        .fetch_optional(conn)
+        .await?
+        .ok_or(ApiError::UserNotExists)?;
+    Ok(uid)
+}
+
+// What the user stores and how the account is used.
+pub async fn user_usage(conn: &mut MySqlConnection, uid: i64) -> Result<Json, ApiError> {
+    let records = record_usage(&mut *conn, uid).await?;
+    let tokens = sqlx::query(
+        "select count(*) as tokens from api_token where uid=? and revoked_at is null and (expires_at is null or expires_at>?)",
+    )
+    .bind(uid)
+    .bind(Utc::now().timestamp())
+    .fetch_one(&mut *conn)
+    .await?;
+    let activity = sqlx::query(
+        "select max(case when action='login.success' then created_at end) as last_login, max(created_at) as last_activity from audit_event where uid=?",
+    )
+    .bind(uid)
+    .fetch_one(&mut *conn)
+    .await?;
+    Ok(json!({
+        "records": records.records,
+        "storage_bytes": records.bytes,
+        "api_tokens": tokens.get::<i64, &str>("tokens"),
+        "last_login": activity.get::<Option<i64>, &str>("last_login"),
+        "last_activity": activity.get::<Option<i64>, &str>("last_activity"),
+    }))
+}
+
+pub async fn admin_activity(req: Request<State>) -> Result<Response, ApiError> {
+    let admin = current_user(&req, Access::Admin).await?;
+    let query: ActivityQuery = req.query().unwrap_or_default();
+
+    let mut conn = req.sqlx_conn::<MySql>().await;
+    let events = audit_events(
+        conn.acquire().await?,
+        query.uid,
+        query.before,
+        query.limit.clamp(1, 200),
+    )
+    .await?;
+    audit(
+        conn.acquire().await?,
+        &req,
+        admin,
+        "admin.activity",
+        json!({"uid": query.uid}),
+    )
+    .await?;
+
+    Ok(succeed(json!(events)))
+}
+
+pub async fn list_users(req: Request<State>) -> Result<Response, ApiError> {
+    let admin = current_user(&req, Access::Admin).await?;
+    let query: UsersQuery = req.query().unwrap_or_default();
+    let q = query.q.trim();
+
+    let mut conn = req.sqlx_conn::<MySql>().await;
+    let rows = sqlx::query(&format!(
+        "select {} from user where (?='' or uid=? or username like ? or email like ?) and (?='' or role=?) and (? is null or (disabled_at is not null)=?) and uid>? order by uid limit ?",
+        USER_COLUMNS
+    ))
+    .bind(q)
+    .bind(q.parse::<i64>().unwrap_or(-1))
+    .bind(like_pattern(q))
+    .bind(like_pattern(q))
+    .bind(&query.role)
+    .bind(&query.role)
+    .bind(query.disabled)
+    .bind(query.disabled)
+    .bind(query.after)
+    .bind(query.limit.clamp(1, 200))
+    .fetch_all(conn.acquire().await?)
+    .await?;
+    audit(
+        conn.acquire().await?,
+        &req,
+        admin,
+        "admin.users.search",
+        json!({"q": q, "role": query.role, "disabled": query.disabled}),
+    )
+    .await?;
+
+    let users: Vec<Json> = rows.iter().map(user_json).collect();
+    Ok(succeed(json!(users)))
+}
+
+// The account with its usage, the event is recorded on the user viewed.
+pub async fn get_user(req: Request<State>) -> Result<Response, ApiError> {
+    let admin = current_user(&req, Access::Admin).await?;
+
+    let mut conn = req.sqlx_conn::<MySql>().await;
+    let uid = target_user(&req, conn.acquire().await?).await?;
+    let mut user = load_user(conn.acquire().await?, uid).await?;
+    user["usage"] = user_usage(conn.acquire().await?, uid).await?;
+    audit(
+        conn.acquire().await?,
+        &req,
+        admin,
+        "admin.user.view",
+        json!({"uid": uid}),
+    )
+    .await?;
+
+    Ok(succeed(json!([user])))
+}
+
+// Refuse the logins, sessions and api tokens of the account until it's enabled again.
+pub async fn disable_user(req: Request<State>) -> Result<Response, ApiError> {
+    let admin = current_user(&req, Access::Admin).await?;
+
+    let mut conn = req.sqlx_conn::<MySql>().await;
+    let uid = target_user(&req, conn.acquire().await?).await?;
+    sqlx::query("update user set disabled_at=? where uid=? and disabled_at is null")
+        .bind(Utc::now().timestamp())
+        .bind(uid)
+        .execute(conn.acquire().await?)
+        .await?;
+    audit(
+        conn.acquire().await?,
+        &req,
+        admin,
+        "admin.user.disable",
+        json!({"uid": uid}),
+    )
+    .await?;
+    log::info!("account disabled", { uid: uid, admin: admin });
+    let user = load_user(conn.acquire().await?, uid).await?;
+
+    Ok(succeed(json!([user])))
+}
+
+pub async fn enable_user(req: Request<State>) -> Result<Response, ApiError> {
+    let admin = current_user(&req, Access::Admin).await?;
+
+    let mut conn = req.sqlx_conn::<MySql>().await;
+    let uid = target_user(&req, conn.acquire().await?).await?;
+    sqlx::query("update user set disabled_at=null where uid=?")
+        .bind(uid)
+        .execute(conn.acquire().await?)
+        .await?;
+    audit(
+        conn.acquire().await?,
+        &req,
+        admin,
+        "admin.user.enable",
+        json!({"uid": uid}),
+    )
+    .await?;
+    log::info!("account enabled", { uid: uid, admin: admin });
+    let user = load_user(conn.acquire().await?, uid).await?;
+
+    Ok(succeed(json!([user])))
+}
+
+// Log the user out and mail a reset link, the password no longer logs in until it's reset.
+pub async fn force_password_reset(req: Request<State>) -> Result<Response, ApiError> {
+    let admin = current_user(&req, Access::Admin).await?;
+
+    let mut conn = req.sqlx_conn::<MySql>().await;
+    let uid = target_user(&req, conn.acquire().await?).await?;
+    sqlx::query("update user set password_reset_required=true, sessions_valid_after=? where uid=?")
+        .bind(Utc::now().timestamp())
+        .bind(uid)
+        .execute(conn.acquire().await?)
+        .await?;
+    let email = sqlx::query("select email from user where uid=?")
+        .bind(uid)
+        .fetch_one(conn.acquire().await?)
+        .await?
+        .get::<String, &str>("email");
+    send_reset_link(conn.acquire().await?, req.state(), uid, email).await?;
+    audit(
+        conn.acquire().await?,
+        &req,
+        admin,
+        "admin.user.password_reset",
+        json!({"uid": uid}),
+    )
+    .await?;
+    log::info!("password reset forced", { uid: uid, admin: admin });
+    let user = load_user(conn.acquire().await?, uid).await?;
+
+    Ok(succeed(json!([user])))
+}
+
+// Log out every session of the user and revoke the api tokens, the password stays.
+pub async fn revoke_sessions(req: Request<State>) -> Result<Response, ApiError> {
+    let admin = current_user(&req, Access::Admin).await?;
+
+    let mut conn = req.sqlx_conn::<MySql>().await;
+    let uid = target_user(&req, conn.acquire().await?).await?;
+    let now = Utc::now().timestamp();
+    sqlx::query("update user set sessions_valid_after=? where uid=?")
+        .bind(now)
+        .bind(uid)
+        .execute(conn.acquire().await?)
+        .await?;
+    let tokens = revoke_api_tokens(conn.acquire().await?, uid, now).await?;
+    audit(
+        conn.acquire().await?,
+        &req,
+        admin,
+        "admin.user.revoke_sessions",
+        json!({"uid": uid, "tokens": tokens}),
+    )
+    .await?;
+    log::info!("sessions revoked", { uid: uid, admin: admin });
+
+    Ok(succeed(json!([{ "tokens": tokens }])))
+}
+
+// The whole dataset as a backup archive, see `write_backup`.
+// The request's transaction keeps every table of it at the same point in time.
+pub async fn backup(req: Request<State>) -> Result<Response, ApiError> {
+    let admin = current_user(&req, Access::Admin).await?;
+
+    let mut conn = req.sqlx_conn::<MySql>().await;
+    let mut archive = Cursor::new(Vec::new());
+    let manifest = write_backup(conn.acquire().await?, &mut archive)
+        .await
+        .map_err(|e| {
+            log::error!("backup failed", { error: e.to_string() });
+            ApiError::Internal
+        })?;
+    let rows: Json = manifest
+        .tables
+        .iter()
+        .map(|it| (it.name.clone(), json!(it.rows)))
+        .collect();
+    audit(
+        conn.acquire().await?,
+        &req,
+        admin,
+        "admin.backup",
+        json!({ "rows": rows }),
+    )
+    .await?;
+    let archive = archive.into_inner();
+    log::info!("backup made", { admin: admin, bytes: archive.len() });
+
+    let filename = format!("finance-backup-{}.zip", Utc::now().format("%Y%m%d%H%M%S"));
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_bytes(archive))
+        .content_type("application/zip")
+        .header(
+            "Content-Disposition",
+            format!("attachment; filename=\"{}\"", filename),
+        )
+        .build())
+}
+
+pub async fn set_role(mut req: Request<State>) -> Result<Response, ApiError> {
+    let admin = current_user(&req, Access::Admin).await?;
+    let RoleBody { role } = body(&mut req).await?;
+
+    let mut conn = req.sqlx_conn::<MySql>().await;
+    let uid = target_user(&req, conn.acquire().await?).await?;
+    let from = sqlx::query("select role from user where uid=?")
+        .bind(uid)
+        .fetch_one(conn.acquire().await?)
+        .await?
+        .get::<String, &str>("role");
+    sqlx::query("update user set role=? where uid=?")
+        .bind(&role)
+        .bind(uid)
+        .execute(conn.acquire().await?)
+        .await?;
+    audit(
+        conn.acquire().await?,
+        &req,
+        admin,
+        "admin.user.role",
+        json!({"uid": uid, "from": from, "to": role}),
+    )
+    .await?;
+    log::info!("role changed", { uid: uid, admin: admin, role: role.as_str() });
+    let user = load_user(conn.acquire().await?, uid).await?;
+
+    Ok(succeed(json!([user])))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::route::admin::like_pattern;
+
+    #[test]
+    fn search_text_is_escaped() {
+        assert_eq!(like_pattern("bob"), "%bob%");
+        assert_eq!(like_pattern("50%_off"), "%50\\%\\_off%");
+        assert_eq!(like_pattern("a\\b"), "%a\\\\b%");
+    }
+}
@@ -0,0 +1,162 @@
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::{json, Value as Json};
+use sqlx::{Acquire, MySql, MySqlConnection, Row};
+use tide::{log, Request, Response};
+use tide_sqlx::SQLxRequestExt;
+
+use crate::state::State;
+use crate::util::prelude::*;
+
+// Tokens are told apart from other secrets by the prefix, e.g. by secret scanners.
+const TOKEN_PREFIX: &str = "fin_";
+
+#[derive(Deserialize)]
+struct TokenBody {
+    #[serde(default, deserialize_with = "lenient")]
+    name: String,
+    #[serde(default, deserialize_with = "lenient")]
+    scope: String,
+    // Days until the token expires, it never does if left out or 0.
+    #[serde(default, deserialize_with = "lenient")]
+    expires_in_days: u32,
+}
+
+impl Validate for TokenBody {
+    fn validate(&self) -> Result<(), ApiError> {
+        Rules::new()
+            .check(
+                "name",
+                (1..=64).contains(&self.name.chars().count()),
+                ApiError::IncorrectTokenName,
+            )
+            .check(
+                "scope",
+                self.scope == "read" || self.scope == "read-write",
+                ApiError::IncorrectTokenScope,
+            )
+            .finish()
+    }
+}
+
+// List the tokens which are not revoked, the tokens themselves are never shown again.
+pub async fn list_tokens(req: Request<State>) -> Result<Response, ApiError> {
+    let uid = current_user(&req, Access::Session).await?;
+
+    let mut conn = req.sqlx_conn::<MySql>().await;
+    let tokens: Vec<Json> = sqlx::query(
+        "select id, name, scope, created_at, expires_at, last_used_at from api_token where uid=? and revoked_at is null order by id",
+    )
+    .bind(uid)
+    .fetch_all(conn.acquire().await?)
+    .await?
+    .into_iter()
+    .map(|row| {
+        json!({
+            "id": row.get::<i64, &str>("id"),
+            "name": row.get::<String, &str>("name"),
+            "scope": row.get::<String, &str>("scope"),
+            "created_at": row.get::<i64, &str>("created_at"),
+            "expires_at": row.get::<Option<i64>, &str>("expires_at"),
+            "last_used_at": row.get::<Option<i64>, &str>("last_used_at"),
+        })
+    })
+    .collect();
+
+    Ok(succeed(json!(tokens)))
+}
+
+pub async fn create_token(mut req: Request<State>) -> Result<Response, ApiError> {
+    let uid = current_user(&req, Access::Session).await?;
+    let TokenBody {
+        name,
+        scope,
+        expires_in_days,
+    } = body(&mut req).await?;
+
+    let token = format!("{}{}", TOKEN_PREFIX, random_token(32));
+    let now = Utc::now().timestamp();
+    let expires_at = match expires_in_days {
+        0 => None,
+        days => Some(now + i64::from(days) * 86400),
+    };
+    let mut conn = req.sqlx_conn::<MySql>().await;
+    let id = sqlx::query(
+        "insert into api_token(uid, name, token_hash, scope, created_at, expires_at) values(?, ?, ?, ?, ?, ?)",
+    )
+    .bind(uid)
+    .bind(&name)
+    .bind(hash_token(&token))
+    .bind(&scope)
+    .bind(now)
+    .bind(expires_at)
+    .execute(conn.acquire().await?)
+    .await?
+    .last_insert_id();
+    audit(
+        conn.acquire().await?,
+        &req,
+        uid,
+        "token.create",
+        json!({"id": id, "name": name, "scope": scope}),
+    )
+    .await?;
+    log::info!("token created", { uid: uid, id: id });
+
+    Ok(succeed(json!([{
+        "id": id,
+        "name": name,
+        "scope": scope,
+        "token": token,
+        "created_at": now,
+        "expires_at": expires_at,
+    }])))
+}
+
+// Revoke every live token of the user, ending the sessions must end the bearer access as well.
+pub async fn revoke_api_tokens(
+    conn: &mut MySqlConnection,
+    uid: i64,
+    now: i64,
+) -> Result<u64, sqlx::Error> {
+    let revoked =
+        sqlx::query("update api_token set revoked_at=? where uid=? and revoked_at is null")
+            .bind(now)
+            .bind(uid)
+            .execute(conn)
+            .await?;
+    Ok(revoked.rows_affected())
+}
+
+// Revoke the token of the path, a token revoked already or of another user is a success as well.
+pub async fn revoke_token(req: Request<State>) -> Result<Response, ApiError> {
+    let uid = current_user(&req, Access::Session).await?;
+    let id = req
+        .param("id")
+        .ok()
+        .and_then(|it| it.parse::<i64>().ok())
+        .unwrap_or_default();
+
+    let mut conn = req.sqlx_conn::<MySql>().await;
+    let revoked = sqlx::query(
+        "update api_token set revoked_at=? where id=? and uid=? and revoked_at is null",
+    )
+    .bind(Utc::now().timestamp())
+    .bind(id)
+    .bind(uid)
+    .execute(conn.acquire().await?)
+    .await?;
+    if revoked.rows_affected() > 0 {
+        audit(
+            conn.acquire().await?,
+            &req,
+            uid,
+            "token.revoke",
+            json!({"id": id}),
+        )
+        .await?;
+        log::info!("token revoked", { uid: uid, id: id });
+    }
+
+    Ok(succeed(json!([])))
+}
@@ -0,0 +1,21 @@
+use serde_json::{json, Value as Json};
+use tide::{Request, Response};
+
+use crate::state::State;
+use crate::util::prelude::*;
+
+// List every response code so that clients could be generated from it instead of the comment.
+pub async fn codes(_req: Request<State>) -> Result<Response, ApiError> {
+    let catalog: Vec<Json> = ApiError::ALL
+        .iter()
+        .map(|err| {
+            json!({
+                "code": err.code(),
+                "status": u16::from(err.status()),
+                "details": err.message(),
+            })
+        })
+        .collect();
+
+    Ok(succeed(json!(catalog)))
+}
@@ -0,0 +1,190 @@
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::{Acquire, MySql, MySqlConnection, Row};
+use tide::{log, Request, Response};
+use tide_sqlx::SQLxRequestExt;
+
+use super::user::verify_password_throttled;
+use crate::state::State;
+use crate::util::prelude::*;
+
+#[derive(Deserialize)]
+struct ChangeBody {
+    #[serde(default, deserialize_with = "lenient")]
+    password: String,
+    #[serde(default, deserialize_with = "lenient")]
+    email: String,
+}
+
+impl Validate for ChangeBody {
+    fn validate(&self) -> Result<(), ApiError> {
+        Rules::new()
+            .check(
+                "password",
+                !self.password.is_empty(),
+                ApiError::IncorrectPasswordFormat,
+            )
+            .check(
+                "email",
+                match_email(&self.email),
+                ApiError::IncorrectEmailFormat,
+            )
+            .finish()
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct TokenQuery {
+    #[serde(default)]
+    token: String,
+}
+
+#[derive(Deserialize)]
+struct TokenBody {
+    #[serde(default, deserialize_with = "lenient")]
+    token: String,
+}
+
+impl Validate for TokenBody {
+    fn validate(&self) -> Result<(), ApiError> {
+        Rules::new()
+            .check("token", !self.token.is_empty(), ApiError::InvalidToken)
+            .finish()
+    }
+}
+
+// Only a hint before the confirmation is mailed, the unique key on the email holds it on confirm.
+async fn email_in_use(conn: &mut MySqlConnection, email: &str) -> Result<bool, ApiError> {
+    let row = sqlx::query("select uid from user where email=? limit 1")
+        .bind(email)
+        .fetch_optional(conn)
+        .await?;
+    Ok(row.is_some())
+}
+
+// Mail a confirmation to the new address and a notice to the old one, nothing changes until it's confirmed.
+pub async fn change_email(mut req: Request<State>) -> Result<Response, ApiError> {
+    let uid = current_user(&req, Access::Session).await?;
+    let ChangeBody { password, email } = body(&mut req).await?;
+    verify_password_throttled(&req, uid, &password, None).await?;
+
+    let mut conn = req.sqlx_conn::<MySql>().await;
+    let row = sqlx::query("select email from user where uid=?")
+        .bind(uid)
+        .fetch_one(conn.acquire().await?)
+        .await?;
+    if email_in_use(conn.acquire().await?, &email).await? {
+        return Err(ApiError::EmailInUse);
+    }
+
+    let config = &req.state().config.email_change;
+    let token = random_token(32);
+    let now = Utc::now().timestamp();
+    // Only the last change asked for can be confirmed.
+    sqlx::query("delete from email_change where uid=? or expires_at<=?")
+        .bind(uid)
+        .bind(now)
+        .execute(conn.acquire().await?)
+        .await?;
+    sqlx::query(
+        "insert into email_change(token_hash, uid, new_email, expires_at) values(?, ?, ?, ?)",
+    )
+    .bind(hash_token(&token))
+    .bind(uid)
+    .bind(&email)
+    .bind(now + config.ttl_secs)
+    .execute(conn.acquire().await?)
+    .await?;
+    log::info!("email change", { uid: uid });
+
+    let confirm = Mail {
+        to: email.clone(),
+        subject: "Confirm your new email".to_string(),
+        body: format!(
+            "Open the link below to use this address for your finance account {}.\n\n{}\n\nThe link expires in {} hours.",
+            uid,
+            config.link.replace("{token}", &token),
+            config.ttl_secs / 3600
+        ),
+    };
+    let notice = Mail {
+        to: row.get::<String, &str>("email"),
+        subject: "Your email is being changed".to_string(),
+        body: format!(
+            "A change of the email of your finance account {} to {} was asked for, it's done once confirmed from the new address.\n\nIf it wasn't you, change your password at once.",
+            uid, email
+        ),
+    };
+    send_in_background(req.state().mailer.clone(), confirm);
+    send_in_background(req.state().mailer.clone(), notice);
+
+    Ok(succeed(json!([])))
+}
+
+// The link mailed to the new address opens this with `?token=`, clients may post `{"token"}` as well.
+pub async fn confirm_email_change(mut req: Request<State>) -> Result<Response, ApiError> {
+    let token = match req.query::<TokenQuery>() {
+        Ok(query) if !query.token.is_empty() => query.token,
+        _ => body::<TokenBody>(&mut req).await?.token,
+    };
+
+    let now = Utc::now().timestamp();
+    let mut conn = req.sqlx_conn::<MySql>().await;
+    // Lock the change, so that two concurrent confirmations can't both apply it.
+    let row = sqlx::query(
+        "select uid, new_email from email_change where token_hash=? and expires_at>? for update",
+    )
+    .bind(hash_token(&token))
+    .bind(now)
+    .fetch_optional(conn.acquire().await?)
+    .await?
+    .ok_or(ApiError::InvalidToken)?;
+    let uid = row.get::<i64, &str>("uid");
+    let new_email = row.get::<String, &str>("new_email");
+    let old_email = sqlx::query("select email from user where uid=?")
+        .bind(uid)
+        .fetch_one(conn.acquire().await?)
+        .await?
+        .get::<String, &str>("email");
+
+    // The mail was received, so that the new address is verified as well.
+    // Another account may have taken the address since, as on register the unique key tells.
+    sqlx::query("update user set email=?, email_dup=0, verified=true where uid=?")
+        .bind(&new_email)
+        .bind(uid)
+        .execute(conn.acquire().await?)
+        .await
+        .map_err(|e| {
+            if is_duplicate_key(&e) {
+                ApiError::EmailInUse
+            } else {
+                ApiError::Internal
+            }
+        })?;
+    sqlx::query("delete from email_change where uid=?")
+        .bind(uid)
+        .execute(conn.acquire().await?)
+        .await?;
+    audit(
+        conn.acquire().await?,
+        &req,
+        uid,
+        "email.change",
+        json!({ "from": old_email, "to": new_email }),
+    )
+    .await?;
+    log::info!("email changed", { uid: uid });
+
+    let notice = Mail {
+        to: old_email,
+        subject: "Your email was changed".to_string(),
+        body: format!(
+            "The email of your finance account {} was changed to {}.\n\nIf it wasn't you, contact the administrator at once.",
+            uid, new_email
+        ),
+    };
+    send_in_background(req.state().mailer.clone(), notice);
+
+    Ok(succeed(json!([])))
+}
@@ -1,4 +1,15 @@
 pub mod prelude;
 
+mod account;
+mod admin;
+mod api_token;
+mod codes;
+mod email_change;
+mod password_reset;
+mod profile;
 mod record;
+mod stats;
+mod two_factor;
+mod usage;
 mod user;
+mod verify;
@@ -0,0 +1,279 @@
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::{Acquire, MySql, MySqlConnection, Row};
+use tide::{log, Request, Response};
+use tide_sqlx::SQLxRequestExt;
+
+use super::api_token::revoke_api_tokens;
+use super::user::{email_name, find_uid};
+use crate::state::State;
+use crate::util::prelude::*;
+
+#[derive(Deserialize)]
+struct ForgotBody {
+    // A uid, username or email, the old clients send it as `uid`.
+    #[serde(default, alias = "uid", deserialize_with = "lenient_name")]
+    login: String,
+}
+
+// The format depends on the config.
+impl Validate for ForgotBody {
+    fn validate_with(&self, state: &State) -> Result<(), ApiError> {
+        Rules::new()
+            .check(
+                "login",
+                state.login_names.parse(&self.login).is_some(),
+                ApiError::IncorrectLoginFormat,
+            )
+            .finish()
+    }
+}
+
+#[derive(Deserialize)]
+struct ResetBody {
+    #[serde(default, deserialize_with = "lenient")]
+    token: String,
+    #[serde(default, deserialize_with = "lenient")]
+    password: String,
+}
+
+impl Validate for ResetBody {
+    fn validate(&self) -> Result<(), ApiError> {
+        Rules::new()
+            // The policy is checked by `reset_password`, it takes the config.
+            .check(
+                "password",
+                !self.password.is_empty(),
+                ApiError::IncorrectPasswordFormat,
+            )
+            .finish()
+    }
+}
+
+// Mail a reset token to the registered address.
+// It succeeds whether the user exists or not, so that it can't be used to find the accounts.
+pub async fn forgot_password(mut req: Request<State>) -> Result<Response, ApiError> {
+    let ForgotBody { login } = body(&mut req).await?;
+    let name = req
+        .state()
+        .login_names
+        .parse(&login)
+        .ok_or(ApiError::IncorrectLoginFormat)?;
+    // An unknown name goes on as uid 0, which no account has.
+    let uid = {
+        let mut conn = req.sqlx_conn::<MySql>().await;
+        find_uid(conn.acquire().await?, &name)
+            .await?
+            .unwrap_or_default()
+    };
+    log::info!("forgot password", { uid: uid });
+
+    // Every request mails the user, so that it's throttled as the login is.
+    let limiter = req.state().login_limiter.clone();
+    let keys = limiter.keys(&req, uid);
+    limiter
+        .check(&keys)
+        .await
+        .map_err(ApiError::TooManyAttempts)?;
+
+    let mut conn = req.sqlx_conn::<MySql>().await;
+    let email = sqlx::query("select email from user where uid=?")
+        .bind(uid)
+        .fetch_optional(conn.acquire().await?)
+        .await?
+        .map(|row| row.get::<String, &str>("email"));
+    let email = match email {
+        Some(email) => email,
+        None => return Ok(succeed(json!([]))),
+    };
+
+    send_reset_link(conn.acquire().await?, req.state(), uid, email).await?;
+
+    Ok(succeed(json!([])))
+}
+
+// Issue a reset token and mail its link, also used when an admin forces a reset.
+pub async fn send_reset_link(
+    conn: &mut MySqlConnection,
+    state: &State,
+    uid: i64,
+    email: String,
+) -> Result<(), ApiError> {
+    let config = &state.config.password_reset;
+    let token = random_token(32);
+    let now = Utc::now().timestamp();
+    sqlx::query("delete from password_reset where expires_at<=?")
+        .bind(now)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query("insert into password_reset(token_hash, uid, expires_at) values(?, ?, ?)")
+        .bind(hash_token(&token))
+        .bind(uid)
+        .bind(now + config.ttl_secs)
+        .execute(&mut *conn)
+        .await?;
+    let mail = Mail {
+        to: email,
+        subject: "Reset your password".to_string(),
+        body: format!(
+            "A password reset was asked for your finance account {}.\n\n{}\n\nThe link expires in {} minutes, ignore this mail if it wasn't you.",
+            uid,
+            config.link.replace("{token}", &token),
+            config.ttl_secs / 60
+        ),
+    };
+    send_in_background(state.mailer.clone(), mail);
+    Ok(())
+}
+
+// Set a new password by the mailed token, every session of the user is logged out and every api token revoked.
+pub async fn reset_password(mut req: Request<State>) -> Result<Response, ApiError> {
+    let ResetBody { token, password } = body(&mut req).await?;
+
+    let now = Utc::now().timestamp();
+    let mut conn = req.sqlx_conn::<MySql>().await;
+    // Lock the token, so that two concurrent resets can't both consume it.
+    let uid = sqlx::query(
+        "select uid from password_reset where token_hash=? and expires_at>? for update",
+    )
+    .bind(hash_token(&token))
+    .bind(now)
+    .fetch_optional(conn.acquire().await?)
+    .await?
+    .map(|row| row.get::<i64, &str>("uid"))
+    .ok_or(ApiError::InvalidToken)?;
+
+    let row = sqlx::query("select email, username from user where uid=?")
+        .bind(uid)
+        .fetch_one(conn.acquire().await?)
+        .await?;
+    let email = row.get::<String, &str>("email");
+    let username = row
+        .get::<Option<String>, &str>("username")
+        .unwrap_or_default();
+    Rules::new()
+        .check_result(
+            "password",
+            req.state()
+                .password_policy
+                .check(&password, &[&username, email_name(&email)]),
+        )
+        .finish()?;
+    let history = req.state().config.password.history;
+    check_reuse(conn.acquire().await?, uid, &password, history).await?;
+    // The mail was received, so that the address is verified as well.
+    sqlx::query("update user set psd=?, sessions_valid_after=?, verified=true, password_reset_required=false where uid=?")
+        .bind(&password)
+        .bind(now)
+        .bind(uid)
+        .execute(conn.acquire().await?)
+        .await?;
+    let tokens = revoke_api_tokens(conn.acquire().await?, uid, now).await?;
+    // The token is single-use, and the other ones of the user are worthless from now on.
+    sqlx::query("delete from password_reset where uid=?")
+        .bind(uid)
+        .execute(conn.acquire().await?)
+        .await?;
+    remember_password(conn.acquire().await?, uid, &password, history).await?;
+    audit(
+        conn.acquire().await?,
+        &req,
+        uid,
+        "password.reset",
+        json!({ "tokens": tokens }),
+    )
+    .await?;
+    log::info!("password reset", { uid: uid });
+
+    Ok(succeed(json!([])))
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Utc;
+    use serde_json::json;
+    use sqlx::mysql::MySqlPoolOptions;
+    use sqlx::Row;
+    use tide::http::{Method, Request, Url};
+    use tide::StatusCode;
+    use tide_sqlx::SQLxMiddleware;
+
+    use crate::config::Config;
+    use crate::route::password_reset::reset_password;
+    use crate::state::State;
+    use crate::util::prelude::*;
+
+    // It takes a MySQL database, as `FINANCE_TEST_DATABASE_URL`, and is skipped without one.
+    #[async_std::test]
+    async fn reset_ends_sessions_and_tokens() {
+        let url = match std::env::var("FINANCE_TEST_DATABASE_URL") {
+            Ok(url) => url,
+            Err(_) => return,
+        };
+        let pool = MySqlPoolOptions::new().connect(&url).await.unwrap();
+        migrate(&pool).await.unwrap();
+        let now = Utc::now().timestamp();
+        let uid = sqlx::query("insert into user(psd, email, verified) values('old', ?, true)")
+            .bind(format!("reset-{}@example.com", random_token(4)))
+            .execute(&pool)
+            .await
+            .unwrap()
+            .last_insert_id() as i64;
+        sqlx::query(
+            "insert into api_token(uid, name, token_hash, scope, created_at) values(?, 'test', ?, 'read', ?)",
+        )
+        .bind(uid)
+        .bind(hash_token(&random_token(16)))
+        .bind(now)
+        .execute(&pool)
+        .await
+        .unwrap();
+        let token = random_token(16);
+        sqlx::query("insert into password_reset(token_hash, uid, expires_at) values(?, ?, ?)")
+            .bind(hash_token(&token))
+            .bind(uid)
+            .bind(now + 600)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let config = Config {
+            database_url: url,
+            ..Config::default()
+        };
+        let mut app = tide::with_state(State::new(config, pool.clone()).await.unwrap());
+        app.with(SQLxMiddleware::from(pool.clone()));
+        app.with(Envelope);
+        app.at("/v2/password/reset").post(api(reset_password));
+        let mut req = Request::new(
+            Method::Post,
+            Url::parse("http://localhost/v2/password/reset").unwrap(),
+        );
+        req.set_body(json!({"token": token, "password": "Vivid-Harbor-Lantern-4821"}));
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+
+        let row = sqlx::query("select sessions_valid_after from user where uid=?")
+            .bind(uid)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert!(row.get::<i64, &str>("sessions_valid_after") >= now);
+        let live = sqlx::query("select count(*) from api_token where uid=? and revoked_at is null")
+            .bind(uid)
+            .fetch_one(&pool)
+            .await
+            .unwrap()
+            .get::<i64, usize>(0);
+        assert_eq!(live, 0);
+
+        for table in ["api_token", "password_history", "audit_event", "user"] {
+            sqlx::query(&format!("delete from {} where uid=?", table))
+                .bind(uid)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+    }
+}
@@ -1,2 +1,13 @@
+pub use super::account::*;
+pub use super::admin::*;
+pub use super::api_token::*;
+pub use super::codes::*;
+pub use super::email_change::*;
+pub use super::password_reset::*;
+pub use super::profile::*;
 pub use super::record::*;
+pub use super::stats::*;
+pub use super::two_factor::*;
+pub use super::usage::*;
 pub use super::user::*;
+pub use super::verify::*;
@@ -0,0 +1,108 @@
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::{Acquire, MySql};
+use tide::{Request, Response};
+use tide_sqlx::SQLxRequestExt;
+
+use crate::state::State;
+use crate::util::prelude::*;
+
+// Every field is optional, only the ones given are changed.
+#[derive(Deserialize)]
+struct ProfileBody {
+    #[serde(default, deserialize_with = "lenient_option")]
+    display_name: Option<String>,
+    #[serde(default, deserialize_with = "lenient_option")]
+    base_currency: Option<String>,
+    #[serde(default, deserialize_with = "lenient_option")]
+    timezone: Option<String>,
+    #[serde(default, deserialize_with = "lenient_option")]
+    locale: Option<String>,
+    #[serde(default, deserialize_with = "lenient_option")]
+    first_day_of_week: Option<u8>,
+    #[serde(default, deserialize_with = "lenient_option")]
+    month_start_day: Option<u8>,
+}
+
+impl Validate for ProfileBody {
+    fn validate(&self) -> Result<(), ApiError> {
+        let valid =
+            |value: &Option<String>, check: fn(&str) -> bool| value.as_deref().is_none_or(check);
+        Rules::new()
+            .check(
+                "display_name",
+                valid(&self.display_name, match_display_name),
+                ApiError::IncorrectProfileFormat,
+            )
+            .check(
+                "base_currency",
+                valid(&self.base_currency, match_currency),
+                ApiError::IncorrectProfileFormat,
+            )
+            .check(
+                "timezone",
+                valid(&self.timezone, match_timezone),
+                ApiError::IncorrectProfileFormat,
+            )
+            .check(
+                "locale",
+                valid(&self.locale, match_locale),
+                ApiError::IncorrectProfileFormat,
+            )
+            .check(
+                "first_day_of_week",
+                self.first_day_of_week
+                    .is_none_or(|it| (1..=7).contains(&it)),
+                ApiError::IncorrectProfileFormat,
+            )
+            .check(
+                "month_start_day",
+                self.month_start_day.is_none_or(|it| (1..=28).contains(&it)),
+                ApiError::IncorrectProfileFormat,
+            )
+            .finish()
+    }
+}
+
+pub async fn get_profile(req: Request<State>) -> Result<Response, ApiError> {
+    // The cookie of a login or an api token with the access needed.
+    let uid = current_user(&req, Access::Read).await?;
+
+    let mut conn = req.sqlx_conn::<MySql>().await;
+    let preferences = Preferences::load(conn.acquire().await?, uid).await?;
+
+    Ok(succeed(json!([preferences])))
+}
+
+// Change the fields given and send back the whole profile.
+pub async fn update_profile(mut req: Request<State>) -> Result<Response, ApiError> {
+    // The cookie of a login or an api token with the access needed.
+    let uid = current_user(&req, Access::Write).await?;
+    let ProfileBody {
+        display_name,
+        base_currency,
+        timezone,
+        locale,
+        first_day_of_week,
+        month_start_day,
+    } = body(&mut req).await?;
+
+    let mut conn = req.sqlx_conn::<MySql>().await;
+    sqlx::query(
+        "update user set display_name=coalesce(?, display_name), base_currency=coalesce(?, base_currency), timezone=coalesce(?, timezone), locale=coalesce(?, locale), first_day_of_week=coalesce(?, first_day_of_week), month_start_day=coalesce(?, month_start_day), profile_updated_at=? where uid=?",
+    )
+    .bind(display_name)
+    .bind(base_currency)
+    .bind(timezone)
+    .bind(locale)
+    .bind(first_day_of_week)
+    .bind(month_start_day)
+    .bind(Utc::now().timestamp())
+    .bind(uid)
+    .execute(conn.acquire().await?)
+    .await?;
+    let preferences = Preferences::load(conn.acquire().await?, uid).await?;
+
+    Ok(succeed(json!([preferences])))
+}
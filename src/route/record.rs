@@ -1,26 +1,19 @@
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value as Json};
-use sqlx::{Acquire, MySql, Row};
-use tide::{log, Request, Response, StatusCode};
+use sqlx::{Acquire, MySql, MySqlConnection, Row};
+use tide::{log, Request, Response};
 use tide_sqlx::SQLxRequestExt;
 
-use crate::util::prelude::check_login;
+use crate::state::State;
+use crate::util::prelude::*;
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Default)]
 struct Query {
     rid: i64,
 }
 
-impl Default for Query {
-    fn default() -> Self {
-        Self {
-            rid: Default::default(),
-        }
-    }
-}
-
 #[derive(Serialize, Deserialize, Clone, Debug)]
-struct Record {
+pub struct Record {
     id: i64,
     date: String,
     record_type: String,
@@ -28,124 +21,146 @@ struct Record {
     is_income: bool,
 }
 
-// This function do not need rid as query.
-pub async fn upload(mut req: Request<()>) -> tide::Result {
-    // Only exists user can login so that there is no necessary to check user's exists.
-    if !check_login(&req).await {
-        return Ok(Response::builder(StatusCode::Accepted)
-            .body(json!({"code":11, "data":[], "details":"USER NOT LOGIN"}))
-            .build());
-    }
-    // If user is logined there must have the cookie which is uid and the format is correct.
-    let uid = req.cookie("uid").unwrap().value().parse::<i64>().unwrap();
-
-    let records_json: Vec<Record> = req.body_json().await?;
-    // Insert data into database.
-    let mut conn = req.sqlx_conn::<MySql>().await;
-    // Insert data and return response.
-
+// Append the records after the last rid of the user, keeping their order and gaps.
+// The count of the records which failed is returned, the others stay inserted.
+pub async fn insert_records(
+    conn: &mut MySqlConnection,
+    uid: i64,
+    records: Vec<Record>,
+) -> Result<usize, ApiError> {
     // Get the difference.
     let max_rid = sqlx::query("select rid from record where uid=?")
         .bind(uid)
-        .fetch_all(conn.acquire().await?)
+        .fetch_all(&mut *conn)
         .await?
         .into_iter()
         .max_by_key(|record| record.get::<i64, &str>("rid"))
         .map(|row| row.get::<i64, &str>("rid"))
         .unwrap_or_default();
-    let start_rid = records_json
-        .clone()
-        .into_iter()
-        .min_by_key(|record| record.id)
+    let start_rid = records
+        .iter()
         .map(|record| record.id)
+        .min()
         .unwrap_or_default();
 
     let mut err_count = 0;
-    for mut ele in records_json {
+    for mut ele in records {
         ele.id += max_rid + 1 - start_rid;
-        let res = sqlx::query(&String::from(format!(
-            "insert into record(uid, rid, details) values('{}', '{}', '{}')",
-            uid,
-            &ele.id,
-            serde_json::to_string(&ele)?
-        )))
-        .execute(conn.acquire().await?)
-        .await;
-        log::info!("{:?}", ele);
+        let res = sqlx::query("insert into record(uid, rid, details) values(?, ?, ?)")
+            .bind(uid)
+            .bind(ele.id)
+            .bind(serde_json::to_string(&ele)?)
+            .execute(&mut *conn)
+            .await;
+        log::debug!("record uploaded", { uid: uid, rid: ele.id });
         if let Err(e) = res {
             err_count += 1;
-            println!("{}", e);
+            log::error!("record upload failed", { uid: uid, rid: ele.id, error: e.to_string() });
+        } else {
+            RECORDS.with_label_values(&["uploaded"]).inc();
         }
     }
+    Ok(err_count)
+}
+
+// This function do not need rid as query.
+pub async fn upload(mut req: Request<State>) -> Result<Response, ApiError> {
+    // The cookie of a login or an api token with the access needed.
+    let uid = current_user(&req, Access::Write).await?;
+    require_verified(&req, uid, "upload").await?;
+
+    let records_json: Vec<Record> = req
+        .body_json()
+        .await
+        .map_err(|_| ApiError::PostDataNotExists)?;
+    let limits = req.state().config.limits.clone();
+    let count = records_json.len() as u64;
+    if limits.max_records_per_upload > 0 && count > limits.max_records_per_upload {
+        return Err(ApiError::TooManyRecords(limits.max_records_per_upload));
+    }
+    // Insert data into database.
+    let mut conn = req.sqlx_conn::<MySql>().await;
+    // Nothing is inserted if the records don't fit in the quota as a whole.
+    // The row of the user is locked till the commit, so that uploads at once can't both pass the count.
+    sqlx::query("select uid from user where uid=? for update")
+        .bind(uid)
+        .execute(conn.acquire().await?)
+        .await?;
+    let usage = record_usage(conn.acquire().await?, uid).await?;
+    let mut bytes = 0;
+    for record in &records_json {
+        bytes += serde_json::to_string(record)?.len() as u64;
+    }
+    check_quota(
+        &limits,
+        usage,
+        RecordUsage {
+            records: count,
+            bytes,
+        },
+    )?;
+    let err_count = insert_records(conn.acquire().await?, uid, records_json).await?;
     if err_count > 0 {
-        return Ok(Response::builder(StatusCode::Accepted)
-            .body(json!({"code":4, "data":[], "details":format!("{} RECORDS FAILED", err_count)}))
-            .build());
+        return Err(ApiError::RecordsFailed(err_count));
     }
 
-    Ok(Response::builder(StatusCode::Ok)
-        .body(json!({"code":0, "data":[], "details":"SUCCESSED"}))
-        .build())
+    Ok(succeed(json!([])))
 }
 
 // Delete from table record whose rid between 0 and end_rid if necessary [0, end_rid].
 // The end_rid default value is 0.
 // The end_rid is include.
-pub async fn delete(mut req: Request<()>) -> tide::Result {
-    // Only exists user can login so that there is no necessary to check user's exists.
-    if !check_login(&req).await {
-        return Ok(Response::builder(StatusCode::Accepted)
-            .body(json!({"code":11, "data":[], "details":"USER NOT LOGIN"}))
-            .build());
-    }
-    // If user is logined there must have the cookie which is uid and the format is correct.
-    let uid = req.cookie("uid").unwrap().value().parse::<i64>().unwrap();
+pub async fn delete(mut req: Request<State>) -> Result<Response, ApiError> {
+    // The cookie of a login or an api token with the access needed.
+    let uid = current_user(&req, Access::Write).await?;
+    require_verified(&req, uid, "delete").await?;
     // Delete from table record whose rid between 0 and end_rid if necessary [0, end_rid], and end_rid default value is 0.
     let end_rid = (req.query::<Query>().unwrap_or_default() as Query).rid;
 
-    let delete_vec: Vec<i64> = req.body_json().await?;
+    let delete_vec: Vec<i64> = req
+        .body_json()
+        .await
+        .map_err(|_| ApiError::PostDataNotExists)?;
     let delete_vec = delete_vec
         .into_iter()
         .filter(|it| it <= &end_rid)
         .map(|it| it.to_string())
         .reduce(|old, item| format!("{}, {}", old, item));
-    if delete_vec.is_none() {
-        return Ok(Response::builder(StatusCode::Ok)
-            .body(json!({"code":0, "data":[], "details":"SUCCESSED"}))
-            .build());
-    }
+    let delete_vec = match delete_vec {
+        Some(delete_vec) => delete_vec,
+        None => return Ok(succeed(json!([]))),
+    };
     // Delete data
     let mut conn = req.sqlx_conn::<MySql>().await;
-    let res = sqlx::query(&String::from(format!(
+    let deleted = sqlx::query(&format!(
         "delete from record where uid={} and rid in ({})",
-        uid,
-        delete_vec.unwrap()
-    )))
+        uid, delete_vec
+    ))
     .execute(conn.acquire().await?)
-    .await;
-    if let Err(_) = res {
-        return Ok(Response::builder(StatusCode::Accepted)
-            .body(json!({"code":5, "data":[], "details":"RECORDS DELETE FAILED"}))
-            .build());
-    }
+    .await
+    .map_err(|_| ApiError::RecordsDeleteFailed)?;
+    RECORDS
+        .with_label_values(&["deleted"])
+        .inc_by(deleted.rows_affected());
+    audit(
+        conn.acquire().await?,
+        &req,
+        uid,
+        "records.delete",
+        json!({"end_rid": end_rid, "deleted": deleted.rows_affected()}),
+    )
+    .await?;
 
-    Ok(Response::builder(StatusCode::Ok)
-        .body(json!({"code":0, "data":[], "details":"SUCCESSED"}))
-        .build())
+    Ok(succeed(json!([])))
 }
 
 // Retry from table record whose rid between start_rid and max_rid if necessary (start_rid, ..].
 // The start_rid default value is 0.
 // The start_rid is exclude.
-pub async fn download(req: Request<()>) -> tide::Result {
-    // Only exists user can login so that there is no necessary to check user's exists.
-    if !check_login(&req).await {
-        return Ok(Response::builder(StatusCode::Accepted)
-            .body(json!({"code":11, "data":[], "details":"USER NOT LOGIN"}))
-            .build());
-    }
-    // If user is logined there must have the cookie which is uid and the format is correct.
-    let uid = req.cookie("uid").unwrap().value().parse::<i64>().unwrap();
+pub async fn download(req: Request<State>) -> Result<Response, ApiError> {
+    // The cookie of a login or an api token with the access needed.
+    let uid = current_user(&req, Access::Read).await?;
+    require_verified(&req, uid, "download").await?;
     // Retry from table record whose rid between start_rid and max_rid if necessary (start_rid, ..], default value is 0.
     let start_rid: i64 = (req.query::<Query>().unwrap_or_default() as Query).rid;
 
@@ -159,8 +174,9 @@ pub async fn download(req: Request<()>) -> tide::Result {
         .into_iter()
         .map(|row| row.get::<Json, usize>(0))
         .collect();
+    RECORDS
+        .with_label_values(&["downloaded"])
+        .inc_by(download_data.len() as u64);
 
-    Ok(Response::builder(StatusCode::Ok)
-        .body(json!({"code":0, "data":json!(download_data), "details":"SUCCESSED"}))
-        .build())
+    Ok(succeed(json!(download_data)))
 }
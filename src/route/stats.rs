@@ -0,0 +1,86 @@
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+use serde_json::{json, Value as Json};
+use sqlx::{Acquire, MySql, Row};
+use tide::{log, Request, Response};
+use tide_sqlx::SQLxRequestExt;
+
+use crate::state::State;
+use crate::util::prelude::*;
+
+#[derive(Deserialize, Default)]
+struct Query {
+    // week or month, month by default.
+    by: String,
+}
+
+#[derive(Default)]
+struct Totals {
+    income: f64,
+    expense: f64,
+    records: u64,
+}
+
+// Income and expense of the records per week or fiscal month, as the preferences of the user tell.
+pub async fn stats(req: Request<State>) -> Result<Response, ApiError> {
+    // The cookie of a login or an api token with the access needed.
+    let uid = current_user(&req, Access::Read).await?;
+    // A view of the records, so that it's restricted as their download is.
+    require_verified(&req, uid, "download").await?;
+    let period = match req.query::<Query>().unwrap_or_default().by.as_str() {
+        "week" => Period::Week,
+        _ => Period::Month,
+    };
+
+    let mut conn = req.sqlx_conn::<MySql>().await;
+    let preferences = Preferences::load(conn.acquire().await?, uid).await?;
+    let rows = sqlx::query("select details from record where uid=?")
+        .bind(uid)
+        .fetch_all(conn.acquire().await?)
+        .await?;
+
+    let mut periods: BTreeMap<_, Totals> = BTreeMap::new();
+    let mut skipped = 0;
+    for row in rows {
+        let record = row.get::<Json, usize>(0);
+        let date = record["date"]
+            .as_str()
+            .and_then(|it| preferences.parse_date(it));
+        let date = match date {
+            Some(date) => date,
+            None => {
+                skipped += 1;
+                continue;
+            }
+        };
+        let totals = periods
+            .entry(preferences.period_start(date, period))
+            .or_default();
+        let amount = record["amount"].as_f64().unwrap_or_default();
+        if record["is_income"].as_bool().unwrap_or_default() {
+            totals.income += amount;
+        } else {
+            totals.expense += amount;
+        }
+        totals.records += 1;
+    }
+    if skipped > 0 {
+        log::debug!("records without a readable date", { uid: uid, skipped: skipped });
+    }
+
+    let data: Vec<Json> = periods
+        .into_iter()
+        .map(|(start, totals)| {
+            json!({
+                "period": start.format("%Y-%m-%d").to_string(),
+                "currency": preferences.base_currency,
+                "income": totals.income,
+                "expense": totals.expense,
+                "records": totals.records,
+            })
+        })
+        .collect();
+
+    Ok(succeed(json!(data)))
+}
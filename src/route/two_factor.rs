@@ -0,0 +1,327 @@
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::{Acquire, MySql, MySqlConnection, Row};
+use tide::{log, Request, Response};
+use tide_sqlx::SQLxRequestExt;
+
+use super::account::cancel_deletion;
+use super::user::{check_standing, verify_password_throttled};
+use crate::state::State;
+use crate::util::prelude::*;
+
+// Wrong codes allowed for one challenge, the login has to start over then.
+const CHALLENGE_ATTEMPTS: u32 = 5;
+
+#[derive(Deserialize)]
+struct CodeBody {
+    #[serde(default, deserialize_with = "lenient")]
+    code: String,
+}
+
+impl Validate for CodeBody {
+    fn validate(&self) -> Result<(), ApiError> {
+        Rules::new()
+            .check(
+                "code",
+                !self.code.is_empty(),
+                ApiError::IncorrectTwoFactorCode,
+            )
+            .finish()
+    }
+}
+
+#[derive(Deserialize)]
+struct DisableBody {
+    #[serde(default, deserialize_with = "lenient")]
+    password: String,
+    #[serde(default, deserialize_with = "lenient")]
+    code: String,
+}
+
+impl Validate for DisableBody {
+    fn validate(&self) -> Result<(), ApiError> {
+        Rules::new()
+            .check(
+                "password",
+                !self.password.is_empty(),
+                ApiError::IncorrectPasswordFormat,
+            )
+            .check(
+                "code",
+                !self.code.is_empty(),
+                ApiError::IncorrectTwoFactorCode,
+            )
+            .finish()
+    }
+}
+
+#[derive(Deserialize)]
+struct ChallengeBody {
+    #[serde(default, deserialize_with = "lenient")]
+    challenge: String,
+    #[serde(default, deserialize_with = "lenient")]
+    code: String,
+}
+
+impl Validate for ChallengeBody {
+    fn validate(&self) -> Result<(), ApiError> {
+        Rules::new()
+            .check(
+                "challenge",
+                !self.challenge.is_empty(),
+                ApiError::InvalidToken,
+            )
+            .check(
+                "code",
+                !self.code.is_empty(),
+                ApiError::IncorrectTwoFactorCode,
+            )
+            .finish()
+    }
+}
+
+// Recovery codes are typed by hand, so that the dash and the case don't matter.
+fn recovery_hash(uid: i64, code: &str) -> String {
+    let code: String = code
+        .chars()
+        .filter(|it| *it != '-')
+        .collect::<String>()
+        .to_lowercase();
+    hash_token(&format!("{}|{}", uid, code))
+}
+
+// Accept a totp code or one of the recovery codes, either is used up by this.
+pub async fn check_second_factor(
+    conn: &mut MySqlConnection,
+    uid: i64,
+    code: &str,
+) -> Result<(), ApiError> {
+    let row = sqlx::query("select totp_secret, totp_last_step from user where uid=?")
+        .bind(uid)
+        .fetch_one(&mut *conn)
+        .await?;
+    let secret = row
+        .get::<Option<String>, &str>("totp_secret")
+        .and_then(|it| base32_decode(&it))
+        .ok_or(ApiError::TwoFactorNotEnabled)?;
+    let last_step = row.get::<i64, &str>("totp_last_step");
+
+    if let Some(step) = verify_totp(&secret, code, Utc::now().timestamp(), last_step) {
+        sqlx::query("update user set totp_last_step=? where uid=?")
+            .bind(step)
+            .bind(uid)
+            .execute(&mut *conn)
+            .await?;
+        return Ok(());
+    }
+    let used = sqlx::query("delete from recovery_code where uid=? and code_hash=?")
+        .bind(uid)
+        .bind(recovery_hash(uid, code))
+        .execute(&mut *conn)
+        .await?;
+    if used.rows_affected() == 1 {
+        log::warn!("recovery code used", { uid: uid });
+        Ok(())
+    } else {
+        Err(ApiError::IncorrectTwoFactorCode)
+    }
+}
+
+// The challenge which the password step of a login hands out, to be completed by `login/2fa`.
+pub async fn start_challenge(req: &Request<State>, uid: i64) -> Result<String, ApiError> {
+    let challenge = random_token(32);
+    let now = Utc::now().timestamp();
+    let mut conn = req.sqlx_conn::<MySql>().await;
+    sqlx::query("delete from login_challenge where expires_at<=?")
+        .bind(now)
+        .execute(conn.acquire().await?)
+        .await?;
+    sqlx::query("insert into login_challenge(token_hash, uid, expires_at) values(?, ?, ?)")
+        .bind(hash_token(&challenge))
+        .bind(uid)
+        .bind(now + req.state().config.two_factor.challenge_ttl_secs)
+        .execute(conn.acquire().await?)
+        .await?;
+    Ok(challenge)
+}
+
+// Make a new secret, it's only enabled once a code of it is verified.
+pub async fn enroll_two_factor(req: Request<State>) -> Result<Response, ApiError> {
+    let uid = current_user(&req, Access::Session).await?;
+
+    let mut conn = req.sqlx_conn::<MySql>().await;
+    let enabled = sqlx::query("select totp_enabled from user where uid=?")
+        .bind(uid)
+        .fetch_one(conn.acquire().await?)
+        .await?
+        .get::<bool, &str>("totp_enabled");
+    if enabled {
+        return Err(ApiError::TwoFactorEnabled);
+    }
+    // 160 bits, as rfc 4226 recommends.
+    let secret = base32_encode(&random_bytes(20));
+    sqlx::query("update user set totp_secret=?, totp_last_step=0 where uid=?")
+        .bind(&secret)
+        .bind(uid)
+        .execute(conn.acquire().await?)
+        .await?;
+    let uri = provisioning_uri(
+        &req.state().config.two_factor.issuer,
+        &uid.to_string(),
+        &secret,
+    );
+
+    Ok(succeed(json!([{"secret": secret, "uri": uri}])))
+}
+
+// Enable the enrolled secret by one of its codes, the recovery codes are sent back only this once.
+pub async fn verify_two_factor(mut req: Request<State>) -> Result<Response, ApiError> {
+    let uid = current_user(&req, Access::Session).await?;
+    let CodeBody { code } = body(&mut req).await?;
+
+    let mut conn = req.sqlx_conn::<MySql>().await;
+    let row = sqlx::query("select totp_secret, totp_enabled from user where uid=?")
+        .bind(uid)
+        .fetch_one(conn.acquire().await?)
+        .await?;
+    if row.get::<bool, &str>("totp_enabled") {
+        return Err(ApiError::TwoFactorEnabled);
+    }
+    let secret = row
+        .get::<Option<String>, &str>("totp_secret")
+        .and_then(|it| base32_decode(&it))
+        .ok_or(ApiError::TwoFactorNotEnabled)?;
+    let step = verify_totp(&secret, &code, Utc::now().timestamp(), 0)
+        .ok_or(ApiError::IncorrectTwoFactorCode)?;
+    sqlx::query("update user set totp_enabled=true, totp_last_step=? where uid=?")
+        .bind(step)
+        .bind(uid)
+        .execute(conn.acquire().await?)
+        .await?;
+
+    sqlx::query("delete from recovery_code where uid=?")
+        .bind(uid)
+        .execute(conn.acquire().await?)
+        .await?;
+    let mut codes = vec![];
+    for _ in 0..req.state().config.two_factor.recovery_codes {
+        let code = random_token(5);
+        let code = format!("{}-{}", &code[..5], &code[5..]);
+        sqlx::query("insert into recovery_code(uid, code_hash) values(?, ?)")
+            .bind(uid)
+            .bind(recovery_hash(uid, &code))
+            .execute(conn.acquire().await?)
+            .await?;
+        codes.push(code);
+    }
+    audit(conn.acquire().await?, &req, uid, "2fa.enable", json!({})).await?;
+    log::info!("two factor enabled", { uid: uid });
+
+    Ok(succeed(json!([{ "recovery_codes": codes }])))
+}
+
+// Turn two factor off, which takes both the password and a code.
+pub async fn disable_two_factor(mut req: Request<State>) -> Result<Response, ApiError> {
+    let uid = current_user(&req, Access::Session).await?;
+    let DisableBody { password, code } = body(&mut req).await?;
+
+    let enabled = {
+        let mut conn = req.sqlx_conn::<MySql>().await;
+        sqlx::query("select totp_enabled from user where uid=?")
+            .bind(uid)
+            .fetch_one(conn.acquire().await?)
+            .await?
+            .get::<bool, &str>("totp_enabled")
+    };
+    if !enabled {
+        return Err(ApiError::TwoFactorNotEnabled);
+    }
+    verify_password_throttled(&req, uid, &password, Some(&code)).await?;
+
+    let mut conn = req.sqlx_conn::<MySql>().await;
+
+    sqlx::query(
+        "update user set totp_secret=null, totp_enabled=false, totp_last_step=0 where uid=?",
+    )
+    .bind(uid)
+    .execute(conn.acquire().await?)
+    .await?;
+    sqlx::query("delete from recovery_code where uid=?")
+        .bind(uid)
+        .execute(conn.acquire().await?)
+        .await?;
+    audit(conn.acquire().await?, &req, uid, "2fa.disable", json!({})).await?;
+    log::info!("two factor disabled", { uid: uid });
+
+    Ok(succeed(json!([])))
+}
+
+// The second step of a login with two factor enabled, the session cookies are issued here.
+pub async fn login_two_factor(mut req: Request<State>) -> Result<Response, ApiError> {
+    let ChallengeBody { challenge, code } = body(&mut req).await?;
+
+    let now = Utc::now().timestamp();
+    let (uid, password) = {
+        let mut conn = req.sqlx_conn::<MySql>().await;
+        let row = sqlx::query(
+            "select uid, attempts from login_challenge where token_hash=? and expires_at>? for update",
+        )
+        .bind(hash_token(&challenge))
+        .bind(now)
+        .fetch_optional(conn.acquire().await?)
+        .await?
+        .ok_or(ApiError::InvalidToken)?;
+        if row.get::<u32, &str>("attempts") >= CHALLENGE_ATTEMPTS {
+            return Err(ApiError::InvalidToken);
+        }
+        let uid = row.get::<i64, &str>("uid");
+        let row =
+            sqlx::query("select psd, disabled_at, password_reset_required from user where uid=?")
+                .bind(uid)
+                .fetch_one(conn.acquire().await?)
+                .await?;
+        // An admin may have stepped in since the password step.
+        check_standing(&row)?;
+        (uid, row.get::<String, &str>("psd"))
+    };
+    log::info!("login two factor", { uid: uid });
+
+    let limiter = req.state().login_limiter.clone();
+    let keys = limiter.keys(&req, uid);
+    limiter
+        .check(&keys)
+        .await
+        .map_err(ApiError::TooManyAttempts)?;
+
+    let mut conn = req.sqlx_conn::<MySql>().await;
+    if let Err(e) = check_second_factor(conn.acquire().await?, uid, &code).await {
+        // The failure is committed with the response, so that the attempts add up.
+        sqlx::query("update login_challenge set attempts=attempts+1 where token_hash=?")
+            .bind(hash_token(&challenge))
+            .execute(conn.acquire().await?)
+            .await?;
+        audit(
+            conn.acquire().await?,
+            &req,
+            uid,
+            "login.failure",
+            json!({"reason": "two_factor"}),
+        )
+        .await?;
+        LOGINS.with_label_values(&["failure"]).inc();
+        limiter.fail(&keys).await;
+        return Err(e);
+    }
+    sqlx::query("delete from login_challenge where token_hash=?")
+        .bind(hash_token(&challenge))
+        .execute(conn.acquire().await?)
+        .await?;
+    drop(conn);
+    cancel_deletion(&req, uid).await?;
+    audit_request(&req, uid, "login.success", json!({"method": "two_factor"})).await?;
+    LOGINS.with_label_values(&["success"]).inc();
+    limiter.succeed(&keys).await;
+
+    Ok(login_response(&req, uid, &password))
+}
@@ -0,0 +1,29 @@
+use serde_json::json;
+use sqlx::{Acquire, MySql};
+use tide::{Request, Response};
+use tide_sqlx::SQLxRequestExt;
+
+use crate::state::State;
+use crate::util::prelude::*;
+
+// A limit of the config, null when it's lifted by 0.
+fn limit(value: u64) -> Option<u64> {
+    Some(value).filter(|it| *it > 0)
+}
+
+// What the user keeps against the quotas, and the limits of a request.
+pub async fn usage(req: Request<State>) -> Result<Response, ApiError> {
+    // The cookie of a login or an api token with the access needed.
+    let uid = current_user(&req, Access::Read).await?;
+    let limits = &req.state().config.limits;
+
+    let mut conn = req.sqlx_conn::<MySql>().await;
+    let usage = record_usage(conn.acquire().await?, uid).await?;
+
+    Ok(succeed(json!([{
+        "records": { "used": usage.records, "limit": limit(limits.max_records_per_user) },
+        "storage_bytes": { "used": usage.bytes, "limit": limit(limits.max_storage_bytes_per_user) },
+        "max_records_per_upload": limit(limits.max_records_per_upload),
+        "max_body_bytes": limit(limits.max_body_bytes),
+    }])))
+}
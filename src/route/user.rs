@@ -1,202 +1,396 @@
 use chrono::Utc;
+use serde::Deserialize;
 use serde_json::json;
-use sqlx::{Acquire, MySql, Row};
-use tide::{http::Cookie, log, Request, Response, StatusCode};
+use sqlx::mysql::MySqlRow;
+use sqlx::{Acquire, MySql, MySqlConnection, Row};
+use tide::{log, Request, Response};
 use tide_sqlx::SQLxRequestExt;
 
+use super::account::cancel_deletion;
+use super::two_factor::{check_second_factor, start_challenge};
+use crate::state::State;
 use crate::util::prelude::*;
 
-pub async fn register(mut req: Request<()>) -> tide::Result {
-    // Get body from request.
-    let body_json = get_json(&mut req).await;
-    // If the body is not exists then return .
-    if body_json.as_ref().is_none() {
-        return Ok(Response::builder(StatusCode::Accepted)
-            .body(json!({"code":10, "data":[], "details":"POST DATA NOT EXISTS"}))
-            .build());
+#[derive(Deserialize)]
+struct RegisterBody {
+    // Left out to have one assigned, as `[account] uid_policy` allows.
+    #[serde(default, deserialize_with = "lenient_id")]
+    uid: IdField,
+    #[serde(default, deserialize_with = "lenient")]
+    username: String,
+    #[serde(default, deserialize_with = "lenient")]
+    password: String,
+    #[serde(default, deserialize_with = "lenient")]
+    email: String,
+}
+
+// The formats depend on the config.
+impl Validate for RegisterBody {
+    fn validate_with(&self, state: &State) -> Result<(), ApiError> {
+        let names = &state.login_names;
+        Rules::new()
+            .check(
+                "uid",
+                match self.uid {
+                    IdField::Missing => uid_allowed(&state.config.account.uid_policy, names, 0),
+                    IdField::Given(0) | IdField::Invalid => false,
+                    IdField::Given(uid) => {
+                        uid_allowed(&state.config.account.uid_policy, names, uid)
+                    }
+                },
+                ApiError::IncorrectUidFormat,
+            )
+            .check(
+                "username",
+                self.username.is_empty() || names.match_username(&self.username),
+                ApiError::IncorrectUsernameFormat,
+            )
+            .check_result(
+                "password",
+                state
+                    .password_policy
+                    .check(&self.password, &[&self.username, email_name(&self.email)]),
+            )
+            .check(
+                "email",
+                match_email(&self.email),
+                ApiError::IncorrectEmailFormat,
+            )
+            .finish()
+    }
+}
+
+#[derive(Deserialize)]
+struct LoginBody {
+    // A uid, username or email, the old clients send it as `uid`.
+    #[serde(default, alias = "uid", deserialize_with = "lenient_name")]
+    login: String,
+    #[serde(default, deserialize_with = "lenient")]
+    password: String,
+}
+
+// The formats depend on the config.
+impl Validate for LoginBody {
+    fn validate_with(&self, state: &State) -> Result<(), ApiError> {
+        Rules::new()
+            .check(
+                "login",
+                state.login_names.parse(&self.login).is_some(),
+                ApiError::IncorrectLoginFormat,
+            )
+            // Only new passwords are held to the policy, the ones set before it still log in.
+            .check(
+                "password",
+                !self.password.is_empty(),
+                ApiError::IncorrectPasswordFormat,
+            )
+            .finish()
+    }
+}
+
+#[derive(Deserialize)]
+struct PasswordBody {
+    #[serde(default, deserialize_with = "lenient")]
+    current_password: String,
+    #[serde(default, deserialize_with = "lenient")]
+    password: String,
+}
+
+impl Validate for PasswordBody {
+    fn validate(&self) -> Result<(), ApiError> {
+        Rules::new()
+            .check(
+                "current_password",
+                !self.current_password.is_empty(),
+                ApiError::IncorrectPasswordFormat,
+            )
+            // The policy is checked by `password`, it takes the config.
+            .check(
+                "password",
+                !self.password.is_empty(),
+                ApiError::IncorrectPasswordFormat,
+            )
+            .finish()
+    }
+}
+
+// Whether a uid given on register is allowed by `[account] uid_policy`, 0 is the one left out.
+// The chosen ones stay below the assigned ones, see `LoginNames::match_chosen_uid`.
+pub fn uid_allowed(policy: &str, names: &LoginNames, uid: i64) -> bool {
+    match (policy, uid) {
+        ("assign", uid) => uid == 0,
+        ("choose", 0) => false,
+        (_, 0) => true,
+        (_, uid) => names.match_chosen_uid(uid),
     }
-    let body_json = body_json.unwrap();
-
-    let uid = body_json
-        .get("uid")
-        .unwrap_or(&json!("0"))
-        .to_string()
-        .parse::<i64>()
-        .unwrap();
-    let password = String::from(
-        body_json
-            .get("password")
-            .unwrap_or(&json!(""))
-            .as_str()
-            .unwrap(),
-    );
-    let email = String::from(
-        body_json
-            .get("email")
-            .unwrap_or(&json!(""))
-            .as_str()
-            .unwrap(),
-    );
-    log::info!("{},{},{}", uid, password, email);
-
-    // Check the format by regex.
-    let res_msg = if !match_id(uid.to_string().as_str()) {
-        Some(json!({"code":21, "data":[], "details":"INCORRECT UID FORMAT"}))
-    } else if !match_password(password.as_str()) {
-        Some(json!({"code":22, "data":[], "details":"INCORRECT PASSWORD FORMAT"}))
-    } else if !match_email(email.as_str()) {
-        Some(json!({"code":23, "data":[], "details":"INCORRECT EMAIL FORMAT"}))
-    } else {
-        None
+}
+
+// The part of the email before the `@`, which a password must not be made of.
+pub fn email_name(email: &str) -> &str {
+    email.split('@').next().unwrap_or_default()
+}
+
+// The uid a login name belongs to, an email shared by several accounts logs in none of them.
+pub async fn find_uid(
+    conn: &mut MySqlConnection,
+    name: &LoginName,
+) -> Result<Option<i64>, ApiError> {
+    let query = match name {
+        LoginName::Uid(uid) => sqlx::query("select uid from user where uid=?").bind(uid),
+        LoginName::Username(username) => {
+            sqlx::query("select uid from user where username=?").bind(username)
+        }
+        LoginName::Email(email) => {
+            sqlx::query("select uid from user where email=? limit 2").bind(email)
+        }
     };
-    if res_msg.is_some() {
-        return Ok(Response::builder(StatusCode::Accepted)
-            .body(res_msg.unwrap())
-            .build());
+    let rows = query.fetch_all(&mut *conn).await?;
+    match rows.as_slice() {
+        [row] => Ok(Some(row.get::<i64, &str>("uid"))),
+        _ => Ok(None),
     }
+}
+
+// An admin may have disabled the account or asked for a new password, the password alone doesn't log in then.
+// The row must have `disabled_at` and `password_reset_required`.
+pub fn check_standing(row: &MySqlRow) -> Result<(), ApiError> {
+    if row.get::<Option<i64>, &str>("disabled_at").is_some() {
+        return Err(ApiError::AccountDisabled);
+    }
+    if row.get::<bool, &str>("password_reset_required") {
+        return Err(ApiError::PasswordResetRequired);
+    }
+    Ok(())
+}
+
+pub async fn register(mut req: Request<State>) -> Result<Response, ApiError> {
+    // Get body from request, the format of every field is checked by the config or by regex.
+    let RegisterBody {
+        uid,
+        username,
+        password,
+        email,
+    } = body(&mut req).await?;
 
-    // Insert data into database.
     let mut conn = req.sqlx_conn::<MySql>().await;
-    sqlx::query(
-        format!(
-            "insert into user(uid, psd, email) values('{}', '{}', '{}')",
-            uid, password, email
-        )
-        .as_str(),
+    // Insert data into database, the account stays unverified until the mailed link is opened.
+    // A null uid is assigned by the auto increment.
+    // The uid, username and email are unique keys, each taken one is refused the same way.
+    let inserted = sqlx::query(
+        "insert into user(uid, username, psd, email, verified) values(?, ?, ?, ?, false)",
     )
+    .bind(uid.given())
+    .bind(Some(&username).filter(|it| !it.is_empty()))
+    .bind(&password)
+    .bind(&email)
     .execute(conn.acquire().await?)
     .await
-    // on succeed
-    .and_then(|_| {
-        Ok(Response::builder(StatusCode::Ok)
-            .body(json!({"code":0, "data":[], "details":"SUCCESSED"}))
-            .build())
-    })
-    // or else user already exists, return statusCode 202 which mean accept but not slove
-    .or_else(|_| {
-        Ok(Response::builder(StatusCode::Accepted)
-            .body(json!({"code":1, "data":[], "details":"USER IS EXISTS"}))
-            .build())
-    })
+    .map_err(|e| {
+        if is_duplicate_key(&e) {
+            ApiError::UserExists
+        } else {
+            ApiError::Internal
+        }
+    })?;
+    let uid = uid
+        .given()
+        .unwrap_or_else(|| inserted.last_insert_id() as i64);
+    log::info!("register", { uid: uid, username: username.as_str(), email: email.as_str() });
+    let history = req.state().config.password.history;
+    remember_password(conn.acquire().await?, uid, &password, history).await?;
+    send_verification(req.state(), uid, &email);
+
+    Ok(succeed(json!([{ "uid": uid }])))
 }
 
-pub async fn login(mut req: Request<()>) -> tide::Result {
-    // Get body from request.
-    let body_json = get_json(&mut req).await;
-    // If the body is not exists then return .
-    if body_json.as_ref().is_none() {
-        return Ok(Response::builder(StatusCode::Accepted)
-            .body(json!({"code":10, "data":[], "details":"POST DATA NOT EXISTS"}))
-            .build());
-    }
-    let body_json = body_json.unwrap();
-    let uid = body_json
-        .get("uid")
-        .unwrap_or(&json!("0"))
-        .to_string()
-        .parse::<i64>()
-        .unwrap();
-    let password = String::from(
-        body_json
-            .get("password")
-            .unwrap_or(&json!(""))
-            .as_str()
-            .unwrap(),
-    );
-    log::info!("{},{}", uid, password);
-
-    // check the format by regex.
-    let res_msg = if !match_id(uid.to_string().as_str()) {
-        Some(json!({"code":21, "data":[], "details":"INCORRECT UID FORMAT"}))
-    } else if !match_password(password.as_str()) {
-        Some(json!({"code":22, "data":[], "details":"INCORRECT PASSWORD FORMAT"}))
-    } else {
-        None
+// Confirm the password of the logged in user before a change of the account, and the second factor if given.
+// It's throttled as a login, so that a stolen cookie isn't enough to guess them either.
+pub async fn verify_password_throttled(
+    req: &Request<State>,
+    uid: i64,
+    password: &str,
+    code: Option<&str>,
+) -> Result<(), ApiError> {
+    let limiter = req.state().login_limiter.clone();
+    let keys = limiter.keys(req, uid);
+    limiter
+        .check(&keys)
+        .await
+        .map_err(ApiError::TooManyAttempts)?;
+
+    let mut conn = req.sqlx_conn::<MySql>().await;
+    let psd = sqlx::query("select psd from user where uid=?")
+        .bind(uid)
+        .fetch_one(conn.acquire().await?)
+        .await?
+        .get::<String, &str>("psd");
+    let checked = match code {
+        _ if psd != password => Err(ApiError::PasswordMismatch),
+        Some(code) => check_second_factor(conn.acquire().await?, uid, code).await,
+        None => Ok(()),
     };
-    if res_msg.is_some() {
-        return Ok(Response::builder(StatusCode::Accepted)
-            .body(res_msg.unwrap())
-            .build());
+    match checked {
+        Ok(()) => limiter.succeed(&keys).await,
+        Err(_) => limiter.fail(&keys).await,
     }
+    checked
+}
+
+pub async fn login(mut req: Request<State>) -> Result<Response, ApiError> {
+    // Get body from request, the format of every field is checked by the config or by regex.
+    let LoginBody { login, password } = body(&mut req).await?;
+    let name = req
+        .state()
+        .login_names
+        .parse(&login)
+        .ok_or(ApiError::IncorrectLoginFormat)?;
+
+    // An unknown name goes on as uid 0, which no account has,
+    // so that it's throttled and refused as an unknown uid always was.
+    let uid = {
+        let mut conn = req.sqlx_conn::<MySql>().await;
+        find_uid(conn.acquire().await?, &name)
+            .await?
+            .unwrap_or_default()
+    };
+    log::info!("login", { uid: uid });
+
+    // Throttle the attempts by ip and uid, repeated failures lock the login for a while.
+    let limiter = req.state().login_limiter.clone();
+    let keys = limiter.keys(&req, uid);
+    limiter
+        .check(&keys)
+        .await
+        .map_err(ApiError::TooManyAttempts)?;
 
     // Fetch password in database if user is exists.
-    let mut conn = req.sqlx_conn::<MySql>().await;
-    let row = sqlx::query("select psd from user where uid=?")
+    // The connection is released at once, `require_verified` takes it again.
+    let row = {
+        let mut conn = req.sqlx_conn::<MySql>().await;
+        sqlx::query(
+            "select psd, totp_enabled, disabled_at, password_reset_required from user where uid=?",
+        )
         .bind(uid)
         .fetch_optional(conn.acquire().await?)
-        .await?;
+        .await?
+    };
     // Return if user is not exists.
-    if row.as_ref().is_none() {
-        return Ok(Response::builder(StatusCode::Accepted)
-            .body(json!({"code":11, "data":[], "details":"USER NOT EXISTS"}))
-            .build());
-    }
-    let row = row.unwrap();
+    let row = match row {
+        Some(row) => row,
+        None => {
+            LOGINS.with_label_values(&["failure"]).inc();
+            limiter.fail(&keys).await;
+            return Err(ApiError::UserNotExists);
+        }
+    };
     let _password = row.get::<String, &str>("psd");
-    if _password == password {
-        let mut res = Response::new(StatusCode::Ok);
-        let _info = format!("{}+{}", uid, Utc::now().timestamp());
-        // The cookie is used to confirm id
-        let info = encrypt_str(&_info, &password).unwrap();
-        res.insert_cookie(Cookie::new("uid", uid.to_string()));
-        res.insert_cookie(Cookie::new("info", info));
-        res.set_body(json!({"code":0, "data":[], "details":"SUCCESSED"}));
-        Ok(res)
-    } else {
-        Ok(Response::builder(StatusCode::Accepted)
-            .body(json!({"code":2, "data":[], "details": "PASSWORD DON'T MATCH"}))
-            .build())
+    if _password != password {
+        // The failure is committed with the response, as every non-internal one is.
+        audit_request(&req, uid, "login.failure", json!({"reason": "password"})).await?;
+        LOGINS.with_label_values(&["failure"]).inc();
+        limiter.fail(&keys).await;
+        return Err(ApiError::PasswordMismatch);
     }
+    check_standing(&row)?;
+    require_verified(&req, uid, "login").await?;
+    // With two factor enabled no cookie is issued until the challenge is completed by `login/2fa`,
+    // and the failures are kept so that the codes can't be guessed between two password steps.
+    if row.get::<bool, &str>("totp_enabled") {
+        let challenge = start_challenge(&req, uid).await?;
+        return Err(ApiError::TwoFactorRequired(challenge));
+    }
+    cancel_deletion(&req, uid).await?;
+    audit_request(&req, uid, "login.success", json!({"method": "password"})).await?;
+    limiter.succeed(&keys).await;
+    LOGINS.with_label_values(&["success"]).inc();
+
+    Ok(login_response(&req, uid, &password))
 }
 
-pub async fn password(mut req: Request<()>) -> tide::Result {
-    // Only exists user can login so that there is no necessary to check user's exists, and the format is correct.
-    if !check_login(&req).await {
-        return Ok(Response::builder(StatusCode::Accepted)
-            .body(json!({"code":11, "data":[], "details":"USER NOT LOGIN"}))
-            .build());
-    }
+pub async fn password(mut req: Request<State>) -> Result<Response, ApiError> {
+    let uid = current_user(&req, Access::Session).await?;
 
-    // Get body from request.
-    let body_json = get_json(&mut req).await;
-    // If the password is not exists then return .
-    if body_json.as_ref().is_none() {
-        return Ok(Response::builder(StatusCode::Accepted)
-            .body(json!({"code":10, "data":[], "details":"POST DATA NOT EXISTS"}))
-            .build());
-    }
-    let body_json = body_json.unwrap();
-
-    // If user is logined there must have the cookie which is uid.
-    let uid = req.cookie("uid").unwrap().value().parse::<i64>().unwrap();
-    let password = String::from(
-        body_json
-            .get("password")
-            .unwrap_or(&json!(""))
-            .as_str()
-            .unwrap(),
-    );
-    // Return if password's format is not right.
-    if !match_password(password.as_str()) {
-        return Ok(Response::builder(StatusCode::Accepted)
-            .body(json!({"code":22, "data":[], "details":"INCORRECT PASSWORD FORMAT"}))
-            .build());
-    }
-    log::info!("{},{}", uid, password);
+    // Get body from request, return if password's format is not right.
+    let PasswordBody {
+        current_password,
+        password,
+    } = body(&mut req).await?;
+
+    require_verified(&req, uid, "password").await?;
+    log::info!("password", { uid: uid });
+    verify_password_throttled(&req, uid, &current_password, None).await?;
 
     let mut conn = req.sqlx_conn::<MySql>().await;
-    let row = sqlx::query("update user set psd=? where uid=?")
-        .bind(password)
+    let row = sqlx::query("select email, username from user where uid=?")
+        .bind(uid)
+        .fetch_one(conn.acquire().await?)
+        .await?;
+
+    let email = row.get::<String, &str>("email");
+    let username = row
+        .get::<Option<String>, &str>("username")
+        .unwrap_or_default();
+    Rules::new()
+        .check_result(
+            "password",
+            req.state()
+                .password_policy
+                .check(&password, &[&username, email_name(&email)]),
+        )
+        .finish()?;
+    let history = req.state().config.password.history;
+    check_reuse(conn.acquire().await?, uid, &password, history).await?;
+    sqlx::query("update user set psd=? where uid=?")
+        .bind(&password)
         .bind(uid)
         .execute(conn.acquire().await?)
-        .await;
-    if row.is_err() {
-        return Ok(Response::builder(StatusCode::Accepted)
-            .body(json!({"code":3, "data":[], "details":"PASSWORD CHANGED FAIL"}))
-            .build());
-    }
+        .await
+        .map_err(|_| ApiError::PasswordChangeFailed)?;
+    remember_password(conn.acquire().await?, uid, &password, history).await?;
+    audit(
+        conn.acquire().await?,
+        &req,
+        uid,
+        "password.change",
+        json!({}),
+    )
+    .await?;
+
+    let mail = Mail {
+        to: email,
+        subject: "Your password was changed".to_string(),
+        body: format!(
+            "The password of your finance account {} was changed at {}.\n\nIf it wasn't you, reset it at once through the forgot password link.",
+            uid,
+            Utc::now().to_rfc2822()
+        ),
+    };
+    send_in_background(req.state().mailer.clone(), mail);
 
-    Ok(Response::builder(StatusCode::Ok)
-        .body(json!({"code":0, "data":[], "details":"SUCCESSED"}))
-        .build())
+    Ok(succeed(json!([])))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::AccountConfig;
+    use crate::route::user::uid_allowed;
+    use crate::util::prelude::LoginNames;
+
+    #[test]
+    fn chosen_uids_stay_below_the_assigned_ones() {
+        let names = LoginNames::new(&AccountConfig::default())
+            .unwrap()
+            .assigned_from(1_000_000_000_000);
+        assert!(uid_allowed("either", &names, 0));
+        assert!(uid_allowed("either", &names, 12345));
+        // Every uid the old clients could choose is still theirs to choose.
+        assert!(uid_allowed("either", &names, 100_000));
+        assert!(uid_allowed("either", &names, 999_999_999_999));
+        assert!(!uid_allowed("either", &names, 1_000_000_000_000));
+        assert!(uid_allowed("choose", &names, 999_999_999_999));
+        assert!(!uid_allowed("choose", &names, 1_000_000_000_001));
+        assert!(!uid_allowed("choose", &names, 0));
+        assert!(!uid_allowed("assign", &names, 12345));
+        assert!(uid_allowed("assign", &names, 0));
+    }
 }
@@ -0,0 +1,73 @@
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::{Acquire, MySql, Row};
+use tide::{log, Request, Response};
+use tide_sqlx::SQLxRequestExt;
+
+use crate::state::State;
+use crate::util::prelude::*;
+
+#[derive(Deserialize, Default)]
+struct TokenQuery {
+    #[serde(default)]
+    token: String,
+}
+
+#[derive(Deserialize)]
+struct TokenBody {
+    #[serde(default, deserialize_with = "lenient")]
+    token: String,
+}
+
+impl Validate for TokenBody {
+    fn validate(&self) -> Result<(), ApiError> {
+        Rules::new()
+            .check("token", !self.token.is_empty(), ApiError::InvalidToken)
+            .finish()
+    }
+}
+
+// The link mailed on register opens this with `?token=`, clients may post `{"token"}` as well.
+pub async fn verify_email(mut req: Request<State>) -> Result<Response, ApiError> {
+    let token = match req.query::<TokenQuery>() {
+        Ok(query) if !query.token.is_empty() => query.token,
+        _ => body::<TokenBody>(&mut req).await?.token,
+    };
+    let uid = token_uid(&token).ok_or(ApiError::InvalidToken)?;
+
+    let mut conn = req.sqlx_conn::<MySql>().await;
+    let email = sqlx::query("select email from user where uid=?")
+        .bind(uid)
+        .fetch_optional(conn.acquire().await?)
+        .await?
+        .map(|row| row.get::<String, &str>("email"))
+        .ok_or(ApiError::InvalidToken)?;
+    let secret = &req.state().config.verification.secret;
+    if !check_verification_token(secret, &token, &email, Utc::now().timestamp()) {
+        return Err(ApiError::InvalidToken);
+    }
+    sqlx::query("update user set verified=true where uid=?")
+        .bind(uid)
+        .execute(conn.acquire().await?)
+        .await?;
+    log::info!("email verified", { uid: uid });
+
+    Ok(succeed(json!([])))
+}
+
+// Mail the link again, e.g. once the first one has expired.
+pub async fn resend_verification(req: Request<State>) -> Result<Response, ApiError> {
+    let uid = current_user(&req, Access::Session).await?;
+
+    let mut conn = req.sqlx_conn::<MySql>().await;
+    let row = sqlx::query("select email, verified from user where uid=?")
+        .bind(uid)
+        .fetch_one(conn.acquire().await?)
+        .await?;
+    if !row.get::<bool, &str>("verified") {
+        send_verification(req.state(), uid, &row.get::<String, &str>("email"));
+    }
+
+    Ok(succeed(json!([])))
+}
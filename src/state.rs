@@ -0,0 +1,45 @@
+use std::io;
+use std::sync::Arc;
+
+use sqlx::MySqlPool;
+use tide::log;
+
+use crate::config::Config;
+use crate::util::prelude::{
+    first_assigned_uid, new_mailer, random_token, LoginLimiter, LoginNames, Mailer, PasswordPolicy,
+};
+
+// The state shared by every request, cheap to clone.
+#[derive(Clone)]
+pub struct State {
+    pub config: Arc<Config>,
+    pub login_limiter: Arc<LoginLimiter>,
+    pub mailer: Arc<dyn Mailer>,
+    pub login_names: Arc<LoginNames>,
+    pub password_policy: Arc<PasswordPolicy>,
+}
+
+impl State {
+    // A broken `[account]` or `[password]` is refused here, before anything is served.
+    // The uids assigned are told apart by the start the migrations left in the database.
+    pub async fn new(mut config: Config, pool: MySqlPool) -> io::Result<Self> {
+        if config.verification.secret.is_empty() {
+            log::warn!("no verification secret configured, the links sent die on restart");
+            config.verification.secret = random_token(32);
+        }
+        let uid_range = |e: sqlx::Error| io::Error::other(format!("uid_range: {}", e));
+        let mut conn = pool.acquire().await.map_err(uid_range)?;
+        let first_assigned = first_assigned_uid(&mut conn).await.map_err(uid_range)?;
+        let login_names = LoginNames::new(&config.account)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("account: {}", e)))?
+            .assigned_from(first_assigned);
+        let password_policy = PasswordPolicy::new(&config.password)?;
+        Ok(Self {
+            login_limiter: Arc::new(LoginLimiter::new(config.rate_limit.clone(), pool)),
+            mailer: new_mailer(&config.mail),
+            login_names: Arc::new(login_names),
+            password_policy: Arc::new(password_policy),
+            config: Arc::new(config),
+        })
+    }
+}
@@ -0,0 +1,495 @@
+use std::fmt;
+use std::future::Future;
+
+use serde_json::{json, Value as Json};
+use sqlx::mysql::MySqlDatabaseError;
+use tide::{Endpoint, Middleware, Next, Request, Response, StatusCode};
+
+use super::api_version::ApiVersion;
+
+/* code: [register, password, login, upload, delete, download]
+0:  SUCCEED                         [register, password, login, upload, delete, download]
+1:  USER IS EXISTS                  [register]
+2:  PASSWORD DON'T MATCH            [login, password, account, email]
+3:  PASSWORD CHANGED FAIL           [password]
+4:  SOME RECORDS FAILED             [upload]
+5:  RECORDS DELETE FAILED           [delete]
+6:  PASSWORD USED RECENTLY          [password, password/reset], one of the last `[password] history` ones
+7:  PASSWORD TOO WEAK               [register, password, password/reset], `data` is `[{"reasons"}]` as listed in the README
+8:  EMAIL IS IN USE                 [email, email/confirm], by another account
+9:  ACCOUNT DISABLED                [login, login/2fa], by an admin
+10: POST DATA NOT EXISTS            [register, password, login]
+11: USER NOT LOGIN/EXISTS           [password, login, upload, delete, download, admin/users/:uid]
+12: TOO MANY ATTEMPTS               [login, password, password/forgot, account, email], `data` is `[{"retry_after"}]` in seconds, also sent as `Retry-After`
+13: EMAIL NOT VERIFIED              [login, password, upload, delete, download], as restricted by the config
+14: INVALID OR EXPIRED TOKEN        [verify-email, password/reset, login/2fa, email/confirm]
+15: TWO FACTOR CODE REQUIRED        [login], `data` is `[{"challenge"}]` to complete by login/2fa
+16: INCORRECT TWO FACTOR CODE       [login/2fa, 2fa/verify, 2fa/disable]
+17: TWO FACTOR ALREADY ENABLED      [2fa/enroll]
+18: TWO FACTOR NOT ENABLED          [2fa/verify, 2fa/disable]
+19: TOKEN SCOPE INSUFFICIENT        [password, upload, delete, 2fa, tokens], a read-only token or any token on the account
+20: ADMIN ONLY                      [admin], the account isn't an admin
+21: INCORRECT UID FORMAT            [register], as `[account] uid_format` and `uid_policy`
+22: INCORRECT PASSWORD FORMAT       [password, login, password/reset], left out or empty
+23: INCORRECT EMAIL FORMAT          [register]
+24: INCORRECT TOKEN NAME            [tokens], 1 to 64 chars
+25: INCORRECT TOKEN SCOPE           [tokens], read or read-write
+26: INCORRECT LOGIN FORMAT          [login, password/forgot], not a uid, username or email the config accepts
+27: INCORRECT USERNAME FORMAT       [register], as `[account] username_format`
+28: INCORRECT PROFILE FORMAT        [profile], see the README for every field
+29: INCORRECT ROLE                  [admin/users/:uid/role], user or admin
+30: PASSWORD RESET REQUIRED         [login, login/2fa], by an admin, the mailed link sets a new password
+31: BODY TOO LARGE                  [all], `data` is `[{"limit"}]` in bytes, as `[limits] max_body_bytes`
+32: TOO MANY RECORDS                [upload], `data` is `[{"limit"}]`, as `[limits] max_records_per_upload`
+33: RECORD QUOTA EXCEEDED           [upload], `data` is `[{"limit", "used"}]`, see `/usage`
+34: STORAGE QUOTA EXCEEDED          [upload], `data` is `[{"limit", "used"}]` in bytes, see `/usage`
+35: CSRF CHECK FAILED               [all], a login cookie sent from a browser without the `X-CSRF-Token` or from an origin not allowed
+99: INTERNAL ERROR                  [all]
+
+When several fields of a request body are wrong, all of them are sent back in `data`
+as `[{"field", "code", "details"}]` while `code` keeps the one of the first field.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ApiError {
+    UserExists,
+    PasswordMismatch,
+    PasswordChangeFailed,
+    RecordsFailed(usize),
+    RecordsDeleteFailed,
+    PasswordReused,
+    WeakPassword(Vec<String>),
+    EmailInUse,
+    AccountDisabled,
+    PostDataNotExists,
+    NotLogin,
+    UserNotExists,
+    TooManyAttempts(u64),
+    EmailNotVerified,
+    InvalidToken,
+    TwoFactorRequired(String),
+    IncorrectTwoFactorCode,
+    TwoFactorEnabled,
+    TwoFactorNotEnabled,
+    TokenScopeInsufficient,
+    AdminOnly,
+    IncorrectUidFormat,
+    IncorrectPasswordFormat,
+    IncorrectEmailFormat,
+    IncorrectTokenName,
+    IncorrectTokenScope,
+    IncorrectLoginFormat,
+    IncorrectUsernameFormat,
+    IncorrectProfileFormat,
+    IncorrectRole,
+    PasswordResetRequired,
+    BodyTooLarge(u64),
+    TooManyRecords(u64),
+    RecordQuotaExceeded(u64, u64),
+    StorageQuotaExceeded(u64, u64),
+    CsrfFailed,
+    Internal,
+    Fields(Vec<FieldError>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub error: ApiError,
+}
+
+impl ApiError {
+    // Every case once, in code order, used to build the code catalog.
+    pub const ALL: &'static [ApiError] = &[
+        ApiError::UserExists,
+        ApiError::PasswordMismatch,
+        ApiError::PasswordChangeFailed,
+        ApiError::RecordsFailed(0),
+        ApiError::RecordsDeleteFailed,
+        ApiError::PasswordReused,
+        ApiError::WeakPassword(vec![]),
+        ApiError::EmailInUse,
+        ApiError::AccountDisabled,
+        ApiError::PostDataNotExists,
+        ApiError::NotLogin,
+        ApiError::UserNotExists,
+        ApiError::TooManyAttempts(0),
+        ApiError::EmailNotVerified,
+        ApiError::InvalidToken,
+        ApiError::TwoFactorRequired(String::new()),
+        ApiError::IncorrectTwoFactorCode,
+        ApiError::TwoFactorEnabled,
+        ApiError::TwoFactorNotEnabled,
+        ApiError::TokenScopeInsufficient,
+        ApiError::AdminOnly,
+        ApiError::IncorrectUidFormat,
+        ApiError::IncorrectPasswordFormat,
+        ApiError::IncorrectEmailFormat,
+        ApiError::IncorrectTokenName,
+        ApiError::IncorrectTokenScope,
+        ApiError::IncorrectLoginFormat,
+        ApiError::IncorrectUsernameFormat,
+        ApiError::IncorrectProfileFormat,
+        ApiError::IncorrectRole,
+        ApiError::PasswordResetRequired,
+        ApiError::BodyTooLarge(0),
+        ApiError::TooManyRecords(0),
+        ApiError::RecordQuotaExceeded(0, 0),
+        ApiError::StorageQuotaExceeded(0, 0),
+        ApiError::CsrfFailed,
+        ApiError::Internal,
+    ];
+
+    // The error which stands for the whole response, it's the first one of the field errors.
+    fn primary(&self) -> &ApiError {
+        match self {
+            ApiError::Fields(errors) => errors.first().map_or(self, |it| it.error.primary()),
+            _ => self,
+        }
+    }
+
+    // The stable numeric code the clients switch on, it must never change once released.
+    pub fn code(&self) -> u16 {
+        match self.primary() {
+            ApiError::UserExists => 1,
+            ApiError::PasswordMismatch => 2,
+            ApiError::PasswordChangeFailed => 3,
+            ApiError::RecordsFailed(_) => 4,
+            ApiError::RecordsDeleteFailed => 5,
+            ApiError::PasswordReused => 6,
+            ApiError::WeakPassword(_) => 7,
+            ApiError::EmailInUse => 8,
+            ApiError::AccountDisabled => 9,
+            ApiError::PostDataNotExists => 10,
+            ApiError::NotLogin | ApiError::UserNotExists => 11,
+            ApiError::TooManyAttempts(_) => 12,
+            ApiError::EmailNotVerified => 13,
+            ApiError::InvalidToken => 14,
+            ApiError::TwoFactorRequired(_) => 15,
+            ApiError::IncorrectTwoFactorCode => 16,
+            ApiError::TwoFactorEnabled => 17,
+            ApiError::TwoFactorNotEnabled => 18,
+            ApiError::TokenScopeInsufficient => 19,
+            ApiError::AdminOnly => 20,
+            ApiError::IncorrectUidFormat => 21,
+            ApiError::IncorrectPasswordFormat => 22,
+            ApiError::IncorrectEmailFormat => 23,
+            ApiError::IncorrectTokenName => 24,
+            ApiError::IncorrectTokenScope => 25,
+            ApiError::IncorrectLoginFormat => 26,
+            ApiError::IncorrectUsernameFormat => 27,
+            ApiError::IncorrectProfileFormat => 28,
+            ApiError::IncorrectRole => 29,
+            ApiError::PasswordResetRequired => 30,
+            ApiError::BodyTooLarge(_) => 31,
+            ApiError::TooManyRecords(_) => 32,
+            ApiError::RecordQuotaExceeded(..) => 33,
+            ApiError::StorageQuotaExceeded(..) => 34,
+            ApiError::CsrfFailed => 35,
+            ApiError::Internal | ApiError::Fields(_) => 99,
+        }
+    }
+
+    // The HTTP status which describes the case by standard semantics.
+    pub fn status(&self) -> StatusCode {
+        match self.primary() {
+            ApiError::UserExists | ApiError::EmailInUse => StatusCode::Conflict,
+            ApiError::PasswordMismatch | ApiError::NotLogin => StatusCode::Unauthorized,
+            ApiError::PasswordChangeFailed
+            | ApiError::RecordsFailed(_)
+            | ApiError::RecordsDeleteFailed
+            | ApiError::Internal => StatusCode::InternalServerError,
+            ApiError::PostDataNotExists => StatusCode::BadRequest,
+            ApiError::PasswordReused | ApiError::WeakPassword(_) => StatusCode::UnprocessableEntity,
+            ApiError::UserNotExists => StatusCode::NotFound,
+            ApiError::TooManyAttempts(_) => StatusCode::TooManyRequests,
+            ApiError::EmailNotVerified
+            | ApiError::AccountDisabled
+            | ApiError::PasswordResetRequired => StatusCode::Forbidden,
+            ApiError::InvalidToken => StatusCode::BadRequest,
+            ApiError::TwoFactorRequired(_) | ApiError::IncorrectTwoFactorCode => {
+                StatusCode::Unauthorized
+            }
+            ApiError::TwoFactorEnabled | ApiError::TwoFactorNotEnabled => StatusCode::Conflict,
+            ApiError::TokenScopeInsufficient
+            | ApiError::AdminOnly
+            | ApiError::CsrfFailed
+            | ApiError::RecordQuotaExceeded(..)
+            | ApiError::StorageQuotaExceeded(..) => StatusCode::Forbidden,
+            ApiError::BodyTooLarge(_) | ApiError::TooManyRecords(_) => StatusCode::PayloadTooLarge,
+            ApiError::IncorrectUidFormat
+            | ApiError::IncorrectPasswordFormat
+            | ApiError::IncorrectEmailFormat
+            | ApiError::IncorrectTokenName
+            | ApiError::IncorrectTokenScope
+            | ApiError::IncorrectLoginFormat
+            | ApiError::IncorrectUsernameFormat
+            | ApiError::IncorrectProfileFormat
+            | ApiError::IncorrectRole
+            | ApiError::Fields(_) => StatusCode::UnprocessableEntity,
+        }
+    }
+
+    // The generic message of the case, as listed in the code catalog.
+    pub fn message(&self) -> &'static str {
+        match self.primary() {
+            ApiError::UserExists => "USER IS EXISTS",
+            ApiError::PasswordMismatch => "PASSWORD DON'T MATCH",
+            ApiError::PasswordChangeFailed => "PASSWORD CHANGED FAIL",
+            ApiError::RecordsFailed(_) => "SOME RECORDS FAILED",
+            ApiError::RecordsDeleteFailed => "RECORDS DELETE FAILED",
+            ApiError::PasswordReused => "PASSWORD USED RECENTLY",
+            ApiError::WeakPassword(_) => "PASSWORD TOO WEAK",
+            ApiError::EmailInUse => "EMAIL IS IN USE",
+            ApiError::AccountDisabled => "ACCOUNT DISABLED",
+            ApiError::PostDataNotExists => "POST DATA NOT EXISTS",
+            ApiError::NotLogin => "USER NOT LOGIN",
+            ApiError::UserNotExists => "USER NOT EXISTS",
+            ApiError::TooManyAttempts(_) => "TOO MANY ATTEMPTS",
+            ApiError::EmailNotVerified => "EMAIL NOT VERIFIED",
+            ApiError::InvalidToken => "INVALID OR EXPIRED TOKEN",
+            ApiError::TwoFactorRequired(_) => "TWO FACTOR CODE REQUIRED",
+            ApiError::IncorrectTwoFactorCode => "INCORRECT TWO FACTOR CODE",
+            ApiError::TwoFactorEnabled => "TWO FACTOR ALREADY ENABLED",
+            ApiError::TwoFactorNotEnabled => "TWO FACTOR NOT ENABLED",
+            ApiError::TokenScopeInsufficient => "TOKEN SCOPE INSUFFICIENT",
+            ApiError::AdminOnly => "ADMIN ONLY",
+            ApiError::IncorrectUidFormat => "INCORRECT UID FORMAT",
+            ApiError::IncorrectPasswordFormat => "INCORRECT PASSWORD FORMAT",
+            ApiError::IncorrectEmailFormat => "INCORRECT EMAIL FORMAT",
+            ApiError::IncorrectTokenName => "INCORRECT TOKEN NAME",
+            ApiError::IncorrectTokenScope => "INCORRECT TOKEN SCOPE",
+            ApiError::IncorrectLoginFormat => "INCORRECT LOGIN FORMAT",
+            ApiError::IncorrectUsernameFormat => "INCORRECT USERNAME FORMAT",
+            ApiError::IncorrectProfileFormat => "INCORRECT PROFILE FORMAT",
+            ApiError::IncorrectRole => "INCORRECT ROLE",
+            ApiError::PasswordResetRequired => "PASSWORD RESET REQUIRED",
+            ApiError::BodyTooLarge(_) => "BODY TOO LARGE",
+            ApiError::TooManyRecords(_) => "TOO MANY RECORDS",
+            ApiError::RecordQuotaExceeded(..) => "RECORD QUOTA EXCEEDED",
+            ApiError::StorageQuotaExceeded(..) => "STORAGE QUOTA EXCEEDED",
+            ApiError::CsrfFailed => "CSRF CHECK FAILED",
+            ApiError::Internal | ApiError::Fields(_) => "INTERNAL ERROR",
+        }
+    }
+
+    // The message sent back with this very error.
+    pub fn details(&self) -> String {
+        match self.primary() {
+            ApiError::RecordsFailed(count) => format!("{} RECORDS FAILED", count),
+            _ => self.message().to_string(),
+        }
+    }
+
+    // The failure a response stands for, if any.
+    pub fn of(res: &Response) -> Option<ApiError> {
+        res.ext::<ApiError>()
+            .or_else(|| res.downcast_error::<ApiError>())
+            .cloned()
+            .or_else(|| res.error().map(|_| ApiError::Internal))
+    }
+
+    // The code a response is answered with, 0 on success.
+    pub fn code_of(res: &Response) -> u16 {
+        ApiError::of(res).map_or(0, |it| it.code())
+    }
+
+    pub fn to_json(&self) -> Json {
+        let data = match self {
+            ApiError::Fields(errors) => errors
+                .iter()
+                .map(|it| {
+                    let mut field = json!({"field":it.field, "code":it.error.code(), "details":it.error.details()});
+                    if let ApiError::WeakPassword(reasons) = &it.error {
+                        field["reasons"] = json!(reasons);
+                    }
+                    field
+                })
+                .collect(),
+            ApiError::TooManyAttempts(secs) => vec![json!({"retry_after":secs})],
+            ApiError::WeakPassword(reasons) => vec![json!({"reasons":reasons})],
+            ApiError::TwoFactorRequired(challenge) => vec![json!({"challenge":challenge})],
+            ApiError::BodyTooLarge(limit) | ApiError::TooManyRecords(limit) => {
+                vec![json!({"limit":limit})]
+            }
+            ApiError::RecordQuotaExceeded(limit, used)
+            | ApiError::StorageQuotaExceeded(limit, used) => {
+                vec![json!({"limit":limit, "used":used})]
+            }
+            _ => vec![],
+        };
+        json!({"code":self.code(), "data":data, "details":self.details()})
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code(), self.details())
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl From<sqlx::Error> for ApiError {
+    fn from(_: sqlx::Error) -> Self {
+        ApiError::Internal
+    }
+}
+
+// Whether the statement broke a unique key, like a taken uid or email, any other error is internal.
+pub fn is_duplicate_key(e: &sqlx::Error) -> bool {
+    match e {
+        sqlx::Error::Database(db) => db
+            .try_downcast_ref::<MySqlDatabaseError>()
+            .is_some_and(|it| it.number() == 1062),
+        _ => false,
+    }
+}
+
+impl From<serde_json::Error> for ApiError {
+    fn from(_: serde_json::Error) -> Self {
+        ApiError::Internal
+    }
+}
+
+// The response of a succeed request, code 0 with the given data.
+pub fn succeed(data: Json) -> Response {
+    Response::builder(StatusCode::Ok)
+        .body(json!({"code":0, "data":data, "details":"SUCCESSED"}))
+        .build()
+}
+
+// Adapt a handler returning `Result<_, ApiError>` into a tide endpoint.
+// The error is kept inside the response so that `Envelope` could render it.
+// Only internal errors are passed to tide as errors, which rolls back the transaction of the sqlx middleware,
+// the other failures keep what has been done, e.g. the records uploaded before one failed.
+pub fn api<State, F, Fut, T>(handler: F) -> impl Endpoint<State>
+where
+    State: Clone + Send + Sync + 'static,
+    F: Fn(Request<State>) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<T, ApiError>> + Send + 'static,
+    T: Into<Response> + 'static,
+{
+    move |req: Request<State>| {
+        let fut = handler(req);
+        async move {
+            match fut.await {
+                Ok(res) => Ok(res.into()),
+                Err(ApiError::Internal) => Err(tide::Error::new(
+                    StatusCode::InternalServerError,
+                    ApiError::Internal,
+                )),
+                Err(err) => {
+                    let mut res = Response::new(err.status());
+                    res.insert_ext(err);
+                    Ok(res)
+                }
+            }
+        }
+    }
+}
+
+// Render every failed response into the `{"code", "data", "details"}` envelope.
+// The status depends on the api version of the request, see `ApiVersion`.
+pub struct Envelope;
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for Envelope {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let version = ApiVersion::of(&req);
+        let mut res = next.run(req).await;
+        let err = match ApiError::of(&res) {
+            Some(err) => err,
+            None => return Ok(res),
+        };
+        res.set_status(version.status(&err));
+        if let ApiError::TooManyAttempts(secs) = err {
+            res.insert_header("Retry-After", secs.to_string());
+        }
+        res.set_body(err.to_json());
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use tide::http::{Method, Request, Response, Url};
+    use tide::StatusCode;
+
+    use crate::util::api_error::{api, ApiError, Envelope};
+
+    #[test]
+    fn catalog_is_complete() {
+        let messages = ApiError::ALL
+            .iter()
+            .map(|it| it.message())
+            .collect::<HashSet<_>>();
+        assert_eq!(messages.len(), ApiError::ALL.len());
+        assert!(ApiError::ALL.iter().all(|it| it.code() != 0));
+    }
+
+    async fn not_login(_req: tide::Request<()>) -> Result<tide::Response, ApiError> {
+        Err(ApiError::NotLogin)
+    }
+
+    async fn call(path: &str, accept: Option<&str>) -> Response {
+        let mut app = tide::new();
+        app.with(Envelope);
+        app.at("/password").post(api(not_login));
+        app.at("/v1/password").post(api(not_login));
+        app.at("/v2/password").post(api(not_login));
+        let url = Url::parse("http://localhost").unwrap().join(path).unwrap();
+        let mut req = Request::new(Method::Post, url);
+        if let Some(accept) = accept {
+            req.insert_header("Accept", accept);
+        }
+        app.respond(req).await.unwrap()
+    }
+
+    #[async_std::test]
+    async fn status_follows_version() {
+        assert_eq!(call("/password", None).await.status(), StatusCode::Accepted);
+        assert_eq!(
+            call("/v1/password", None).await.status(),
+            StatusCode::Accepted
+        );
+        assert_eq!(
+            call("/v2/password", None).await.status(),
+            StatusCode::Unauthorized
+        );
+        let v2 = Some("application/vnd.finance.v2+json");
+        assert_eq!(
+            call("/password", v2).await.status(),
+            StatusCode::Unauthorized
+        );
+        assert_eq!(
+            call("/v1/password", v2).await.status(),
+            StatusCode::Accepted
+        );
+
+        let mut res = call("/v2/password", None).await;
+        // Only internal errors reach tide as errors, so that the sqlx middleware still commits.
+        assert!(res.ext().get::<ApiError>().is_some());
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(body["code"], 11);
+    }
+
+    async fn throttled(_req: tide::Request<()>) -> Result<tide::Response, ApiError> {
+        Err(ApiError::TooManyAttempts(42))
+    }
+
+    #[async_std::test]
+    async fn throttled_sends_retry_after() {
+        let mut app = tide::new();
+        app.with(Envelope);
+        app.at("/v2/login").post(api(throttled));
+        let url = Url::parse("http://localhost/v2/login").unwrap();
+        let mut res: Response = app.respond(Request::new(Method::Post, url)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::TooManyRequests);
+        assert_eq!(res["Retry-After"], "42");
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(body["code"], 12);
+        assert_eq!(body["data"][0]["retry_after"], 42);
+    }
+}
@@ -0,0 +1,52 @@
+use tide::{Request, StatusCode};
+
+use super::api_error::ApiError;
+
+// The media type which opts in the v2 behavior without changing the path.
+const V2_MEDIA_TYPE: &str = "application/vnd.finance.v2+json";
+
+/* version:
+v1: every failure is answered with 202, which the android client relies on. It's the default.
+v2: failures are answered with the standard status of the case, see `ApiError::status`.
+    Selected by the `/v2` prefix or by `Accept: application/vnd.finance.v2+json`.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApiVersion {
+    V1,
+    V2,
+}
+
+impl ApiVersion {
+    // The path prefix wins over the header, so that `/v1` could never be upgraded by accident.
+    pub fn of<State>(req: &Request<State>) -> Self {
+        let path = req.url().path();
+        if path.starts_with("/v2/") {
+            return ApiVersion::V2;
+        }
+        if path.starts_with("/v1/") {
+            return ApiVersion::V1;
+        }
+        match req.header("Accept") {
+            Some(values) if values.iter().any(|it| it.as_str().contains(V2_MEDIA_TYPE)) => {
+                ApiVersion::V2
+            }
+            _ => ApiVersion::V1,
+        }
+    }
+
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            ApiVersion::V1 => "/v1",
+            ApiVersion::V2 => "/v2",
+        }
+    }
+
+    // The HTTP status a failure is answered with.
+    pub fn status(&self, err: &ApiError) -> StatusCode {
+        match self {
+            ApiVersion::V1 if *err == ApiError::Internal => StatusCode::InternalServerError,
+            ApiVersion::V1 => StatusCode::Accepted,
+            ApiVersion::V2 => err.status(),
+        }
+    }
+}
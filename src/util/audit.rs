@@ -0,0 +1,94 @@
+use chrono::Utc;
+use serde_json::{json, Value as Json};
+use sqlx::{Acquire, MySql, MySqlConnection, Row};
+use tide::Request;
+use tide_sqlx::SQLxRequestExt;
+
+use super::api_error::ApiError;
+use super::rate_limit::client_ip;
+use crate::state::State;
+
+// Append an event of the user to the audit log, in the same transaction as the change it records.
+pub async fn audit(
+    conn: &mut MySqlConnection,
+    req: &Request<State>,
+    uid: i64,
+    action: &str,
+    details: Json,
+) -> Result<(), ApiError> {
+    let ip = client_ip(req, req.state().config.rate_limit.trust_proxy);
+    let user_agent = req
+        .header("User-Agent")
+        .map(|it| it.last().as_str())
+        .unwrap_or_default();
+    insert_audit_event(conn, uid, action, &ip, user_agent, details).await
+}
+
+// `audit` without a request, e.g. for `finance-admin`.
+pub async fn insert_audit_event(
+    conn: &mut MySqlConnection,
+    uid: i64,
+    action: &str,
+    ip: &str,
+    user_agent: &str,
+    details: Json,
+) -> Result<(), ApiError> {
+    let user_agent: String = user_agent.chars().take(255).collect();
+    sqlx::query(
+        "insert into audit_event(uid, action, ip, user_agent, details, created_at) values(?, ?, ?, ?, ?, ?)",
+    )
+    .bind(uid)
+    .bind(action)
+    .bind(ip)
+    .bind(user_agent)
+    .bind(details)
+    .bind(Utc::now().timestamp())
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+// `audit` for handlers which don't hold the connection of the request at the moment.
+pub async fn audit_request(
+    req: &Request<State>,
+    uid: i64,
+    action: &str,
+    details: Json,
+) -> Result<(), ApiError> {
+    let mut conn = req.sqlx_conn::<MySql>().await;
+    audit(conn.acquire().await?, req, uid, action, details).await
+}
+
+// A page of the audit log, newest first, of one user or of everyone.
+// `before` is the id of the last event of the previous page, 0 for the first page.
+pub async fn audit_events(
+    conn: &mut MySqlConnection,
+    uid: Option<i64>,
+    before: i64,
+    limit: u32,
+) -> Result<Vec<Json>, ApiError> {
+    let events = sqlx::query(
+        "select id, uid, action, ip, user_agent, details, created_at from audit_event where (? is null or uid=?) and (?=0 or id<?) order by id desc limit ?",
+    )
+    .bind(uid)
+    .bind(uid)
+    .bind(before)
+    .bind(before)
+    .bind(limit)
+    .fetch_all(conn)
+    .await?
+    .into_iter()
+    .map(|row| {
+        json!({
+            "id": row.get::<i64, &str>("id"),
+            "uid": row.get::<i64, &str>("uid"),
+            "action": row.get::<String, &str>("action"),
+            "ip": row.get::<String, &str>("ip"),
+            "user_agent": row.get::<String, &str>("user_agent"),
+            "details": row.get::<Json, &str>("details"),
+            "created_at": row.get::<i64, &str>("created_at"),
+        })
+    })
+    .collect();
+    Ok(events)
+}
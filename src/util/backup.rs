@@ -0,0 +1,371 @@
+use std::error::Error;
+use std::io::{Read, Seek, Write};
+
+use chrono::Utc;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use futures_lite::StreamExt;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value as Json;
+use sqlx::any::{AnyArguments, AnyConnection, AnyKind};
+use sqlx::query::Query;
+use sqlx::{Any, Connection, Executor, MySqlConnection, Row};
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+use super::migration::MIGRATOR;
+
+pub type BackupResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+// The name of a table and its rows, as read from an archive.
+pub type TableRows = (String, Vec<Json>);
+
+// Told apart from other zips by the manifest, the version changes with the layout of the archive.
+pub const BACKUP_FORMAT: &str = "finance-backup";
+pub const BACKUP_VERSION: u32 = 1;
+
+// The tables kept by a backup, the user first. The links, challenges and throttles mailed
+// or counted for a while are left out, they are worthless once restored.
+pub const BACKUP_TABLES: &[&str] = &[
+    "user",
+    "record",
+    "password_history",
+    "recovery_code",
+    "api_token",
+    "audit_event",
+];
+
+const MANIFEST: &str = "manifest.json";
+
+// The schema SQLite is restored into, as the migrations leave MySQL.
+const SQLITE_SCHEMA: &str = include_str!("../../schema/sqlite.sql");
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Manifest {
+    pub format: String,
+    pub version: u32,
+    pub created_at: i64,
+    // The last migration applied to the database backed up.
+    pub schema_version: i64,
+    pub tables: Vec<TableEntry>,
+}
+
+// `<name>.jsonl` in the archive, one json object per row.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TableEntry {
+    pub name: String,
+    pub rows: u64,
+    pub sha256: String,
+}
+
+fn sha256_hex(bytes: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input(bytes);
+    hasher.result_str()
+}
+
+fn match_column(name: &str) -> bool {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"^[a-z_][a-z0-9_]*$").unwrap();
+    }
+    RE.is_match(name)
+}
+
+// Write the archive of the tables given as their json lines, with the manifest checking them.
+pub fn write_archive<W: Write + Seek>(
+    writer: W,
+    schema_version: i64,
+    tables: &[(&str, Vec<u8>)],
+) -> BackupResult<Manifest> {
+    let mut zip = ZipWriter::new(writer);
+    let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+    let mut manifest = Manifest {
+        format: BACKUP_FORMAT.to_string(),
+        version: BACKUP_VERSION,
+        created_at: Utc::now().timestamp(),
+        schema_version,
+        tables: Vec::new(),
+    };
+    for (name, lines) in tables {
+        zip.start_file(format!("{}.jsonl", name), options)?;
+        zip.write_all(lines)?;
+        manifest.tables.push(TableEntry {
+            name: name.to_string(),
+            rows: lines.iter().filter(|it| **it == b'\n').count() as u64,
+            sha256: sha256_hex(lines),
+        });
+    }
+    zip.start_file(MANIFEST, options)?;
+    zip.write_all(&serde_json::to_vec_pretty(&manifest)?)?;
+    zip.finish()?;
+    Ok(manifest)
+}
+
+// Dump every table of `BACKUP_TABLES` from MySQL, in one consistent snapshot if the
+// connection is in a transaction.
+pub async fn write_backup<W: Write + Seek>(
+    conn: &mut MySqlConnection,
+    writer: W,
+) -> BackupResult<Manifest> {
+    let schema_version = sqlx::query(
+        "select cast(coalesce(max(version), 0) as signed) from _sqlx_migrations where success=1",
+    )
+    .fetch_one(&mut *conn)
+    .await?
+    .get::<i64, usize>(0);
+
+    let mut tables = Vec::new();
+    for table in BACKUP_TABLES {
+        let columns: Vec<String> = sqlx::query(
+            "select column_name from information_schema.columns where table_schema=database() and table_name=? order by ordinal_position",
+        )
+        .bind(table)
+        .fetch_all(&mut *conn)
+        .await?
+        .into_iter()
+        .map(|row| row.get::<String, usize>(0))
+        .collect();
+        // MySQL writes every row as json itself, the booleans become 0 and 1.
+        let pairs = columns
+            .iter()
+            .map(|it| format!("'{}', `{}`", it, it))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let query = format!("select json_object({}) from {}", pairs, table);
+        let mut lines = Vec::new();
+        let mut rows = sqlx::query(&query).fetch(&mut *conn);
+        while let Some(row) = rows.next().await {
+            serde_json::to_writer(&mut lines, &row?.get::<Json, usize>(0))?;
+            lines.push(b'\n');
+        }
+        drop(rows);
+        tables.push((*table, lines));
+    }
+    write_archive(writer, schema_version, &tables)
+}
+
+// Read the manifest and the rows of every table, refusing an archive which doesn't check out.
+pub fn read_archive<R: Read + Seek>(reader: R) -> BackupResult<(Manifest, Vec<TableRows>)> {
+    let mut zip = ZipArchive::new(reader)?;
+    let manifest: Manifest = serde_json::from_reader(zip.by_name(MANIFEST)?)?;
+    if manifest.format != BACKUP_FORMAT {
+        return Err(format!("not a {} archive", BACKUP_FORMAT).into());
+    }
+    if manifest.version > BACKUP_VERSION {
+        return Err(format!(
+            "archive version {} is newer than this build",
+            manifest.version
+        )
+        .into());
+    }
+    let mut tables = Vec::new();
+    for entry in &manifest.tables {
+        if !BACKUP_TABLES.contains(&entry.name.as_str()) {
+            return Err(format!("unknown table {}", entry.name).into());
+        }
+        let mut lines = Vec::new();
+        zip.by_name(&format!("{}.jsonl", entry.name))?
+            .read_to_end(&mut lines)?;
+        if sha256_hex(&lines) != entry.sha256 {
+            return Err(format!("checksum of {} doesn't match", entry.name).into());
+        }
+        let mut rows = Vec::new();
+        for line in lines.split(|it| *it == b'\n').filter(|it| !it.is_empty()) {
+            let row: Json = serde_json::from_slice(line)?;
+            let columns_ok = row
+                .as_object()
+                .is_some_and(|it| it.keys().all(|key| match_column(key)));
+            if !columns_ok {
+                return Err(format!("a row of {} isn't an object of columns", entry.name).into());
+            }
+            rows.push(row);
+        }
+        if rows.len() as u64 != entry.rows {
+            return Err(format!(
+                "{} has {} rows, the manifest says {}",
+                entry.name,
+                rows.len(),
+                entry.rows
+            )
+            .into());
+        }
+        tables.push((entry.name.clone(), rows));
+    }
+    Ok((manifest, tables))
+}
+
+fn bind_value<'q>(
+    query: Query<'q, Any, AnyArguments<'q>>,
+    value: &Json,
+) -> Query<'q, Any, AnyArguments<'q>> {
+    match value {
+        Json::Null => query.bind(None::<String>),
+        Json::Bool(it) => query.bind(*it),
+        Json::Number(it) => match it.as_i64() {
+            Some(int) => query.bind(int),
+            None => query.bind(it.as_f64().unwrap_or_default()),
+        },
+        Json::String(it) => query.bind(it.clone()),
+        // The json columns, MySQL parses the text and SQLite keeps it.
+        other => query.bind(other.to_string()),
+    }
+}
+
+// Load an archive into the empty database of the url, MySQL or SQLite.
+// The schema is made first, by the migrations for MySQL. Nothing is loaded unless all of it is.
+pub async fn restore_backup<R: Read + Seek>(reader: R, url: &str) -> BackupResult<Manifest> {
+    let (manifest, tables) = read_archive(reader)?;
+    let known = MIGRATOR
+        .iter()
+        .map(|it| it.version)
+        .max()
+        .unwrap_or_default();
+    if manifest.schema_version > known {
+        return Err(format!(
+            "the archive has schema {}, this build knows up to {}",
+            manifest.schema_version, known
+        )
+        .into());
+    }
+
+    let mut conn = AnyConnection::connect(url).await?;
+    match conn.kind() {
+        AnyKind::MySql => {
+            let mut mysql = MySqlConnection::connect(url).await?;
+            MIGRATOR.run(&mut mysql).await?;
+            mysql.close().await?;
+        }
+        AnyKind::Sqlite => {
+            conn.execute(SQLITE_SCHEMA).await?;
+        }
+    }
+
+    let mut tx = conn.begin().await?;
+    for table in BACKUP_TABLES {
+        let count = sqlx::query(&format!("select count(*) from {}", table))
+            .fetch_one(&mut tx)
+            .await?
+            .get::<i64, usize>(0);
+        if count > 0 {
+            return Err(format!("the database isn't empty, {} has rows", table).into());
+        }
+    }
+    for (table, rows) in &tables {
+        for row in rows {
+            let row = row.as_object().ok_or("a row isn't an object")?;
+            let columns: Vec<&str> = row.keys().map(String::as_str).collect();
+            let sql = format!(
+                "insert into {}({}) values({})",
+                table,
+                columns
+                    .iter()
+                    .map(|it| format!("`{}`", it))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                vec!["?"; columns.len()].join(", ")
+            );
+            let mut query = sqlx::query(&sql);
+            for value in row.values() {
+                query = bind_value(query, value);
+            }
+            query.execute(&mut tx).await?;
+        }
+    }
+    tx.commit().await?;
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Cursor, Read, Write};
+
+    use serde_json::json;
+    use sqlx::{AnyConnection, Connection, Row};
+    use zip::write::FileOptions;
+    use zip::{ZipArchive, ZipWriter};
+
+    use crate::util::backup::{read_archive, restore_backup, write_archive};
+
+    fn lines(rows: &[serde_json::Value]) -> Vec<u8> {
+        rows.iter()
+            .map(|it| format!("{}\n", it))
+            .collect::<String>()
+            .into_bytes()
+    }
+
+    fn archive() -> Vec<u8> {
+        let user = lines(&[
+            json!({"uid": 100000, "psd": "secret", "email": "a@b.com", "username": null, "verified": 1}),
+        ]);
+        let record = lines(&[
+            json!({"uid": 100000, "rid": 1, "details": {"id": 1, "amount": 2.5}}),
+            json!({"uid": 100000, "rid": 2, "details": {"id": 2, "amount": 4.0}}),
+        ]);
+        let mut buffer = Cursor::new(Vec::new());
+        write_archive(&mut buffer, 0, &[("user", user), ("record", record)]).unwrap();
+        buffer.into_inner()
+    }
+
+    #[test]
+    fn archives_are_verified() {
+        let (manifest, tables) = read_archive(Cursor::new(archive())).unwrap();
+        assert_eq!(manifest.tables.len(), 2);
+        assert_eq!(manifest.tables[1].rows, 2);
+        assert_eq!(tables[1].1[1]["details"]["amount"], json!(4.0));
+
+        // The rows changed under the same manifest.
+        let mut original = ZipArchive::new(Cursor::new(archive())).unwrap();
+        let mut manifest = String::new();
+        original
+            .by_name("manifest.json")
+            .unwrap()
+            .read_to_string(&mut manifest)
+            .unwrap();
+        let mut broken = ZipWriter::new(Cursor::new(Vec::new()));
+        broken
+            .start_file("user.jsonl", FileOptions::default())
+            .unwrap();
+        broken
+            .write_all(&lines(&[json!({"uid": 100000, "role": "admin"})]))
+            .unwrap();
+        broken
+            .start_file("record.jsonl", FileOptions::default())
+            .unwrap();
+        broken
+            .start_file("manifest.json", FileOptions::default())
+            .unwrap();
+        broken.write_all(manifest.as_bytes()).unwrap();
+        let broken = broken.finish().unwrap().into_inner();
+        let error = read_archive(Cursor::new(broken)).unwrap_err();
+        assert_eq!(error.to_string(), "checksum of user doesn't match");
+        assert!(read_archive(Cursor::new(b"not a zip".to_vec())).is_err());
+    }
+
+    #[async_std::test]
+    async fn archives_restore_into_sqlite() {
+        let path = std::env::temp_dir().join(format!("finance-restore-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let url = format!("sqlite://{}?mode=rwc", path.display());
+        restore_backup(Cursor::new(archive()), &url).await.unwrap();
+        // A database with rows isn't restored into.
+        assert!(restore_backup(Cursor::new(archive()), &url).await.is_err());
+
+        let mut conn = AnyConnection::connect(&url).await.unwrap();
+        let row = sqlx::query("select count(*), max(rid) from record")
+            .fetch_one(&mut conn)
+            .await
+            .unwrap();
+        assert_eq!(row.get::<i64, usize>(0), 2);
+        assert_eq!(row.get::<i64, usize>(1), 2);
+        let role = sqlx::query("select role from user where uid=100000")
+            .fetch_one(&mut conn)
+            .await
+            .unwrap()
+            .get::<String, usize>(0);
+        assert_eq!(role, "user");
+        conn.close().await.unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -1,17 +1,36 @@
 use chrono::{Duration, TimeZone, Utc};
+use serde_json::json;
 use sqlx::{Acquire, MySql, Row};
-use tide::Request;
+use tide::{Request, Response};
 use tide_sqlx::SQLxRequestExt;
 
-use super::prelude::decrypt_str;
+use super::prelude::{
+    csrf_cookie, decrypt_str, encrypt_str, hash_token, session_cookie, succeed, ApiError,
+    CSRF_HEADER,
+};
+use crate::state::State;
 
-pub async fn check_login(req: &Request<()>) -> bool {
+// The succeed response of a login, carrying the session cookies.
+pub fn login_response(req: &Request<State>, uid: i64, password: &str) -> Response {
+    let mut res = succeed(json!([]));
+    let _info = format!("{}+{}", uid, Utc::now().timestamp());
+    // The cookie is used to confirm id
+    let info = encrypt_str(&_info, password).unwrap();
+    res.insert_cookie(session_cookie(req, "uid", uid.to_string()));
+    res.insert_cookie(session_cookie(req, "info", info));
+    // A new token with every login, the browser clients send it back on every change.
+    let csrf = csrf_cookie(req);
+    res.insert_header(CSRF_HEADER, csrf.value());
+    res.insert_cookie(csrf);
+    res
+}
+
+pub async fn check_login(req: &Request<State>) -> bool {
     // Get uid from cookie.
     let uid = req
         .cookie("uid")
-        .and_then(|it| Some(it.value().parse::<i64>().unwrap_or_default()))
-        .or_else(|| Some(0i64))
-        .unwrap();
+        .map(|it| it.value().parse::<i64>().unwrap_or_default())
+        .unwrap_or(0i64);
     // Get password by uid from database.
     // Actually if uid is exists the password must be exists so that could unwrap dirextlly
     let mut conn = req.sqlx_conn::<MySql>().await;
@@ -20,14 +39,18 @@ pub async fn check_login(req: &Request<()>) -> bool {
         return false;
     }
     let conn = conn.unwrap();
-    let row = sqlx::query("select psd from user where uid=?")
-        .bind(uid)
-        .fetch_optional(conn)
-        .await;
+    let row = sqlx::query(
+        "select psd, sessions_valid_after from user where uid=? and disabled_at is null",
+    )
+    .bind(uid)
+    .fetch_optional(conn)
+    .await;
     if row.as_ref().is_err() || row.as_ref().unwrap().is_none() {
         return false;
     }
-    let password = row.unwrap().unwrap().get::<String, &str>("psd");
+    let row = row.unwrap().unwrap();
+    let password = row.get::<String, &str>("psd");
+    let valid_after = row.get::<i64, &str>("sessions_valid_after");
     // Check info to confirm whether the user is logged in.
     match req.cookie("info") {
         Some(info_cookie) => {
@@ -37,16 +60,96 @@ pub async fn check_login(req: &Request<()>) -> bool {
                 return false;
             }
             let info = info.unwrap();
-            let ans = info.split("+").collect::<Vec<_>>();
-            if ans[0].parse::<i64>().unwrap_or_default() != uid {
+            // The info is `uid+timestamp`, anything else is refused.
+            let (_uid, timestamp) = match info.split_once('+') {
+                Some(ans) => ans,
+                None => return false,
+            };
+            if _uid.parse::<i64>().ok() != Some(uid) {
                 return false;
             }
-            let timestamp = ans[1].parse::<i64>().unwrap();
-            let dt = Utc.timestamp(timestamp, 0);
+            let dt = match timestamp
+                .parse::<i64>()
+                .ok()
+                .and_then(|it| Utc.timestamp_opt(it, 0).single())
+            {
+                Some(dt) => dt,
+                None => return false,
+            };
 
-            // expires in 3 days
-            dt + Duration::days(3) > Utc::now()
+            // expires in 3 days, and dies with every session once they are revoked,
+            // the one issued in the second of the revoke as well
+            dt + Duration::days(3) > Utc::now() && dt.timestamp() > valid_after
         }
         None => false,
     }
 }
+
+// What a request needs to be allowed, api tokens are scoped to the records.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    // The account itself, only the cookie of a login has it.
+    Session,
+    // The session of an account with the admin role.
+    Admin,
+}
+
+// The api token sent as `Authorization: Bearer`, any other authorization is left to the cookie.
+pub fn bearer_token<S>(req: &Request<S>) -> Option<String> {
+    req.header("Authorization")
+        .and_then(|it| it.last().as_str().strip_prefix("Bearer "))
+        .map(|it| it.trim().to_string())
+}
+
+// The uid of the request, by `Authorization: Bearer` if sent or else by the login cookie.
+pub async fn current_user(req: &Request<State>, access: Access) -> Result<i64, ApiError> {
+    let token = match bearer_token(req) {
+        Some(token) => token,
+        None => {
+            if !check_login(req).await {
+                return Err(ApiError::NotLogin);
+            }
+            // If user is logined there must have the cookie which is uid and the format is correct.
+            let uid = req.cookie("uid").unwrap().value().parse::<i64>().unwrap();
+            if access == Access::Admin {
+                let mut conn = req.sqlx_conn::<MySql>().await;
+                let role = sqlx::query("select role from user where uid=?")
+                    .bind(uid)
+                    .fetch_one(conn.acquire().await?)
+                    .await?
+                    .get::<String, &str>("role");
+                if role != "admin" {
+                    return Err(ApiError::AdminOnly);
+                }
+            }
+            return Ok(uid);
+        }
+    };
+
+    let now = Utc::now().timestamp();
+    let mut conn = req.sqlx_conn::<MySql>().await;
+    let row = sqlx::query(
+        "select api_token.id, api_token.uid, api_token.scope from api_token join user on user.uid=api_token.uid where api_token.token_hash=? and api_token.revoked_at is null and (api_token.expires_at is null or api_token.expires_at>?) and user.disabled_at is null",
+    )
+    .bind(hash_token(&token))
+    .bind(now)
+    .fetch_optional(conn.acquire().await?)
+    .await?
+    .ok_or(ApiError::NotLogin)?;
+    let allowed = match access {
+        Access::Read => true,
+        Access::Write => row.get::<String, &str>("scope") == "read-write",
+        Access::Session | Access::Admin => false,
+    };
+    if !allowed {
+        return Err(ApiError::TokenScopeInsufficient);
+    }
+    sqlx::query("update api_token set last_used_at=? where id=?")
+        .bind(now)
+        .bind(row.get::<i64, &str>("id"))
+        .execute(conn.acquire().await?)
+        .await?;
+    Ok(row.get::<i64, &str>("uid"))
+}
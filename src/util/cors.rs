@@ -0,0 +1,103 @@
+use tide::http::headers::HeaderValue;
+use tide::security::{CorsMiddleware, Origin};
+use tide::{Middleware, Next, Request};
+
+use super::csrf::{same_origin, CSRF_HEADER};
+use crate::config::CorsConfig;
+
+// The cors middleware of tide, which refuses any origin out of its list.
+// The pages served by the server itself are let through to `Csrf`, as they are no cross origin.
+pub struct Cors {
+    inner: CorsMiddleware,
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for Cors {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let origin = req.header("Origin").map(|it| it.last().as_str());
+        if origin.is_some_and(|origin| same_origin(&req, origin)) {
+            return Ok(next.run(req).await);
+        }
+        self.inner.handle(req, next).await
+    }
+}
+
+// Let the browser clients of `[cors] allowed_origins` call the api with their cookies,
+// none is built without an origin since the requests of any other one are refused.
+pub fn cors(config: &CorsConfig) -> Option<Cors> {
+    if config.allowed_origins.is_empty() {
+        return None;
+    }
+    let header = |value: String| value.parse::<HeaderValue>().unwrap();
+    Some(Cors {
+        inner: CorsMiddleware::new()
+            .allow_origin(Origin::List(config.allowed_origins.clone()))
+            .allow_methods(header("GET, POST, PUT, PATCH, DELETE, OPTIONS".to_string()))
+            .allow_headers(header(format!(
+                "Content-Type, Authorization, {}",
+                CSRF_HEADER
+            )))
+            .expose_headers(header(format!("Retry-After, {}", CSRF_HEADER)))
+            .allow_credentials(true)
+            .max_age(header(config.max_age_secs.to_string())),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use tide::http::{Method, Request, Url};
+    use tide::StatusCode;
+
+    use crate::config::CorsConfig;
+    use crate::util::cors::cors;
+
+    #[async_std::test]
+    async fn only_allowed_origins_are_answered() {
+        assert!(cors(&CorsConfig::default()).is_none());
+
+        let mut app = tide::new();
+        app.with(
+            cors(&CorsConfig {
+                allowed_origins: vec!["https://web.example.com".to_string()],
+                max_age_secs: 600,
+            })
+            .unwrap(),
+        );
+        app.at("/v2/usage")
+            .get(|_| async { Ok("ok") })
+            .post(|_| async { Ok("ok") });
+        let call = |method, origin: &str| {
+            let mut req = Request::new(method, Url::parse("http://localhost/v2/usage").unwrap());
+            req.insert_header("Host", "localhost");
+            req.insert_header("Origin", origin);
+            req.insert_header("Access-Control-Request-Method", "GET");
+            app.respond::<_, tide::http::Response>(req)
+        };
+
+        let res = call(Method::Options, "https://web.example.com")
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(
+            res["Access-Control-Allow-Origin"],
+            "https://web.example.com"
+        );
+        assert_eq!(res["Access-Control-Allow-Credentials"], "true");
+        assert_eq!(res["Access-Control-Max-Age"], "600");
+        let res = call(Method::Get, "https://web.example.com").await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(
+            res["Access-Control-Allow-Origin"],
+            "https://web.example.com"
+        );
+
+        // The pages of the server itself are no cross origin, `Csrf` takes care of them.
+        let res = call(Method::Post, "http://localhost").await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert!(res.header("Access-Control-Allow-Origin").is_none());
+
+        let res = call(Method::Get, "https://evil.example.com").await.unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+        assert!(res.header("Access-Control-Allow-Origin").is_none());
+    }
+}
@@ -52,7 +52,7 @@ fn encrypt(data: &[u8], key: &[u8]) -> Result<Vec<u8>, symmetriccipher::Symmetri
                 .take_read_buffer()
                 .take_remaining()
                 .iter()
-                .map(|&i| i),
+                .copied(),
         );
 
         match result {
@@ -91,7 +91,7 @@ fn decrypt(
                 .take_read_buffer()
                 .take_remaining()
                 .iter()
-                .map(|&i| i),
+                .copied(),
         );
         match result {
             BufferResult::BufferUnderflow => break,
@@ -111,38 +111,51 @@ fn convert_key(key: &str) -> [u8; 32] {
     key
 }
 
-// There are no errors in the encryption process os that U can safely unwrap.
+// Encode every byte as three decimal digits, it's the format the `info` cookie is sent in.
+fn encode_digits(data: &[u8]) -> String {
+    data.iter().map(|ele| format!("{:03}", ele)).collect()
+}
+
+// The reverse of `encode_digits`, any malformed input such as `999` or `abc` gives None.
+pub fn decode_digits(message: &str) -> Option<Vec<u8>> {
+    let message = message.as_bytes();
+    if !message.len().is_multiple_of(3) {
+        return None;
+    }
+    message
+        .chunks(3)
+        .map(|chunk| {
+            if !chunk.iter().all(u8::is_ascii_digit) {
+                return None;
+            }
+            std::str::from_utf8(chunk).ok()?.parse::<u8>().ok()
+        })
+        .collect()
+}
+
 pub fn encrypt_str(message: &str, key: &str) -> Option<String> {
     let key = convert_key(key);
-    let encrypted_data = encrypt(message.as_bytes(), &key).unwrap();
-    let mut res = String::new();
-    for ele in &encrypted_data {
-        res += format!("{:03}", ele).as_str();
-    }
+    let encrypted_data = encrypt(message.as_bytes(), &key).ok()?;
 
-    Some(res)
+    Some(encode_digits(&encrypted_data))
 }
 
-// The encryption process may cause errors due to key mismatch.
+// The decryption may fail due to key mismatch or a malformed message, it never panics.
 pub fn decrypt_str(message: &str, key: &str) -> Option<String> {
     let key = convert_key(key);
-
-    let mut encrypted_data: Vec<u8> = Vec::new();
-    for pos in 0..message.len() / 3 {
-        let start_pos = pos * 3;
-        let value = &message[start_pos..start_pos + 3].parse::<u8>().unwrap();
-        encrypted_data.push(*value);
-    }
+    let encrypted_data = decode_digits(message)?;
 
     match decrypt(&encrypted_data, &key) {
-        Ok(data) => String::from_utf8(data.to_owned()).map_or_else(|_| None, |it| Some(it)),
+        Ok(data) => String::from_utf8(data).ok(),
         Err(_) => None,
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::util::cryp::{decrypt_str, encrypt_str};
+    use proptest::prelude::*;
+
+    use crate::util::cryp::{decode_digits, decrypt_str, encode_digits, encrypt_str};
 
     #[test]
     fn test1() {
@@ -167,4 +180,35 @@ mod test {
         // println!("{}", decrypted_data);
         assert!(da1 == da2);
     }
+
+    #[test]
+    fn malformed_cookies_are_refused() {
+        // Malformed cookies are refused instead of crashing the request.
+        for info in ["999", "abc", "12", "0000", "１２３", "+++"] {
+            assert_eq!(decrypt_str(info, "1234567"), None);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn decrypt_str_is_total(message in ".*", key in ".*") {
+            let _ = decrypt_str(&message, &key);
+        }
+
+        #[test]
+        fn decrypt_str_of_digits_is_total(message in "[0-9]{0,96}", key in "[[:alnum:]]{7,15}") {
+            let _ = decrypt_str(&message, &key);
+        }
+
+        #[test]
+        fn digits_round_trip(data in proptest::collection::vec(any::<u8>(), 0..64)) {
+            prop_assert_eq!(decode_digits(&encode_digits(&data)), Some(data));
+        }
+
+        #[test]
+        fn encrypt_round_trip(message in ".*", key in ".*") {
+            let encrypted_data = encrypt_str(&message, &key).unwrap();
+            prop_assert_eq!(decrypt_str(&encrypted_data, &key), Some(message));
+        }
+    }
 }
@@ -0,0 +1,183 @@
+use tide::http::cookies::SameSite;
+use tide::http::{Cookie, Method, Url};
+use tide::{Middleware, Next, Request, Response};
+
+use super::api_error::ApiError;
+use super::check_login::bearer_token;
+use super::tls::Tls;
+use super::token::random_token;
+
+// The double-submit token, set as a cookie on login and sent back by the browser clients as the header.
+pub const CSRF_COOKIE: &str = "csrf";
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+
+// The token cookie of a login, unlike the session cookies the scripts of the page have to read it.
+pub fn csrf_cookie<State>(req: &Request<State>) -> Cookie<'static> {
+    let mut cookie = Cookie::new(CSRF_COOKIE, random_token(16));
+    if req.ext::<Tls>().is_some() {
+        cookie.set_secure(true);
+        cookie.set_same_site(SameSite::Lax);
+    }
+    cookie
+}
+
+// Whether the origin is the one of the server itself, as the `Host` of the request tells.
+pub fn same_origin<State>(req: &Request<State>, origin: &str) -> bool {
+    let host = req.header("Host").map(|it| it.last().as_str());
+    match Url::parse(origin) {
+        Ok(url) => {
+            let origin_host = match url.port() {
+                Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+                None => url.host_str().unwrap_or_default().to_string(),
+            };
+            host == Some(origin_host.as_str())
+        }
+        Err(_) => false,
+    }
+}
+
+// Refuse the state-changing requests of a login cookie which a browser may have been tricked into.
+// A browser sends `Origin` or `Sec-Fetch-Site`, such requests must come from the server itself
+// or an allowed origin and carry the token of the `csrf` cookie as `X-CSRF-Token`.
+// The native clients send neither and an api token is never sent by the browser on its own,
+// so both are let through as before. Any other `Authorization` still logs in by the cookie.
+pub struct Csrf {
+    allowed_origins: Vec<String>,
+}
+
+impl Csrf {
+    pub fn new(allowed_origins: Vec<String>) -> Self {
+        Self { allowed_origins }
+    }
+
+    // Whether the origin is the one of the server, or one of the config.
+    fn allows_origin<State>(&self, req: &Request<State>, origin: &str) -> bool {
+        self.allowed_origins.iter().any(|it| it == origin) || same_origin(req, origin)
+    }
+
+    fn check<State>(&self, req: &Request<State>) -> Result<(), ApiError> {
+        if matches!(
+            req.method(),
+            Method::Get | Method::Head | Method::Options | Method::Trace
+        ) || bearer_token(req).is_some()
+            || req.cookie("uid").is_none()
+        {
+            return Ok(());
+        }
+        let origin = req.header("Origin").map(|it| it.last().as_str());
+        if origin.is_none() && req.header("Sec-Fetch-Site").is_none() {
+            return Ok(());
+        }
+        if let Some(origin) = origin {
+            if !self.allows_origin(req, origin) {
+                return Err(ApiError::CsrfFailed);
+            }
+        }
+        let cookie = req.cookie(CSRF_COOKIE);
+        let header = req.header(CSRF_HEADER).map(|it| it.last().as_str());
+        match (cookie, header) {
+            (Some(cookie), Some(header)) if !header.is_empty() && cookie.value() == header => {
+                Ok(())
+            }
+            _ => Err(ApiError::CsrfFailed),
+        }
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for Csrf {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        if let Err(err) = self.check(&req) {
+            let mut res = Response::new(err.status());
+            res.insert_ext(err);
+            return Ok(res);
+        }
+        // Echo the token, a client of another origin can't read the cookie but may read the header.
+        let token = req.cookie(CSRF_COOKIE).map(|it| it.value().to_string());
+        let mut res = next.run(req).await;
+        if let Some(token) = token {
+            if res.header(CSRF_HEADER).is_none() {
+                res.insert_header(CSRF_HEADER, token);
+            }
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tide::http::{Method, Request, Url};
+    use tide::StatusCode;
+
+    use crate::util::api_error::Envelope;
+    use crate::util::csrf::Csrf;
+
+    async fn call(method: Method, headers: &[(&str, &str)]) -> StatusCode {
+        let mut app = tide::new();
+        app.with(Envelope);
+        app.with(Csrf::new(vec!["https://web.example.com".to_string()]));
+        app.at("/v2/upload")
+            .get(|_| async { Ok("ok") })
+            .post(|_| async { Ok("ok") });
+        let url = Url::parse("http://api.example.com/v2/upload").unwrap();
+        let mut req = Request::new(method, url);
+        req.insert_header("Host", "api.example.com");
+        for (name, value) in headers {
+            req.insert_header(*name, *value);
+        }
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        res.status()
+    }
+
+    #[async_std::test]
+    async fn cookie_requests_of_browsers_need_the_token() {
+        let cookies = ("Cookie", "uid=10001; info=x; csrf=abc");
+        let token = ("X-CSRF-Token", "abc");
+        let web = ("Origin", "https://web.example.com");
+        let same = ("Origin", "http://api.example.com");
+        let evil = ("Origin", "https://evil.example.com");
+
+        // Native clients and api tokens send no origin, reads change nothing.
+        assert_eq!(call(Method::Post, &[cookies]).await, StatusCode::Ok);
+        assert_eq!(
+            call(
+                Method::Post,
+                &[cookies, evil, ("Authorization", "Bearer t")]
+            )
+            .await,
+            StatusCode::Ok
+        );
+        assert_eq!(call(Method::Get, &[cookies, evil]).await, StatusCode::Ok);
+        assert_eq!(call(Method::Post, &[evil]).await, StatusCode::Ok);
+
+        assert_eq!(
+            call(Method::Post, &[cookies, web, token]).await,
+            StatusCode::Ok
+        );
+        assert_eq!(
+            call(Method::Post, &[cookies, same, token]).await,
+            StatusCode::Ok
+        );
+        assert_eq!(
+            call(Method::Post, &[cookies, web]).await,
+            StatusCode::Forbidden
+        );
+        assert_eq!(
+            call(Method::Post, &[cookies, web, ("X-CSRF-Token", "abd")]).await,
+            StatusCode::Forbidden
+        );
+        assert_eq!(
+            call(Method::Post, &[cookies, evil, token]).await,
+            StatusCode::Forbidden
+        );
+        assert_eq!(
+            call(Method::Post, &[cookies, ("Sec-Fetch-Site", "cross-site")]).await,
+            StatusCode::Forbidden
+        );
+        // Only a bearer token is taken instead of the cookie.
+        assert_eq!(
+            call(Method::Post, &[cookies, evil, ("Authorization", "Basic x")]).await,
+            StatusCode::Forbidden
+        );
+    }
+}
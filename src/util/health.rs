@@ -0,0 +1,40 @@
+use serde_json::json;
+use sqlx::MySqlPool;
+use tide::{Endpoint, Request, Response, StatusCode};
+
+use super::migration::pending_migrations;
+use super::shutdown::Shutdown;
+use crate::state::State;
+
+// Liveness, the process is able to answer. It never touches the database.
+pub async fn healthz(_req: Request<State>) -> tide::Result {
+    Ok(Response::builder(StatusCode::Ok)
+        .body(json!({"status":"ok"}))
+        .build())
+}
+
+// Readiness, a pooled connection works and every migration is applied.
+// It turns unready as soon as the shutdown begins so that the balancer stops sending requests.
+pub fn readyz(pool: MySqlPool, shutdown: Shutdown) -> impl Endpoint<State> {
+    move |_req: Request<State>| {
+        let pool = pool.clone();
+        let shutdown = shutdown.clone();
+        async move {
+            let (status, details) = if shutdown.is_stopping() {
+                (StatusCode::ServiceUnavailable, json!("SHUTTING DOWN"))
+            } else {
+                match pending_migrations(&pool).await {
+                    Ok(pending) if pending.is_empty() => (StatusCode::Ok, json!("READY")),
+                    Ok(pending) => (
+                        StatusCode::ServiceUnavailable,
+                        json!({"pending_migrations": pending}),
+                    ),
+                    Err(e) => (StatusCode::ServiceUnavailable, json!(e.to_string())),
+                }
+            };
+            Ok(Response::builder(status)
+                .body(json!({"status":details}))
+                .build())
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use futures_lite::AsyncReadExt;
+use sqlx::{MySqlConnection, Row};
+use tide::{Middleware, Next, Request, Response};
+
+use super::api_error::ApiError;
+use crate::config::LimitsConfig;
+
+// What the records of a user take, by count and as stored.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RecordUsage {
+    pub records: u64,
+    pub bytes: u64,
+}
+
+pub async fn record_usage(
+    conn: &mut MySqlConnection,
+    uid: i64,
+) -> Result<RecordUsage, sqlx::Error> {
+    let row = sqlx::query(
+        "select count(*), cast(coalesce(sum(json_storage_size(details)), 0) as signed) from record where uid=?",
+    )
+    .bind(uid)
+    .fetch_one(conn)
+    .await?;
+    Ok(RecordUsage {
+        records: row.get::<i64, usize>(0) as u64,
+        bytes: row.get::<i64, usize>(1) as u64,
+    })
+}
+
+// Whether the records added to the usage stay within the limits of the config.
+// The bytes added are told by their json, which is about what they take once stored.
+pub fn check_quota(
+    limits: &LimitsConfig,
+    usage: RecordUsage,
+    added: RecordUsage,
+) -> Result<(), ApiError> {
+    let max = limits.max_records_per_user;
+    if max > 0 && usage.records + added.records > max {
+        return Err(ApiError::RecordQuotaExceeded(max, usage.records));
+    }
+    let max = limits.max_storage_bytes_per_user;
+    if max > 0 && usage.bytes + added.bytes > max {
+        return Err(ApiError::StorageQuotaExceeded(max, usage.bytes));
+    }
+    Ok(())
+}
+
+// Refuse the bodies over `[limits] max_body_bytes` before a handler reads them.
+// A declared length is checked at once, a chunked body is read up to the limit.
+pub struct BodyLimit {
+    max_bytes: u64,
+}
+
+impl BodyLimit {
+    pub fn new(max_bytes: u64) -> Self {
+        Self { max_bytes }
+    }
+
+    fn refuse(&self) -> Response {
+        let err = ApiError::BodyTooLarge(self.max_bytes);
+        let mut res = Response::new(err.status());
+        res.insert_ext(err);
+        res
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for BodyLimit {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        if self.max_bytes == 0 {
+            return Ok(next.run(req).await);
+        }
+        match req.len() {
+            Some(len) if len as u64 > self.max_bytes => return Ok(self.refuse()),
+            Some(_) => {}
+            None => {
+                let mut bytes = Vec::new();
+                req.take_body()
+                    .take(self.max_bytes + 1)
+                    .read_to_end(&mut bytes)
+                    .await?;
+                if bytes.len() as u64 > self.max_bytes {
+                    return Ok(self.refuse());
+                }
+                req.set_body(bytes);
+            }
+        }
+        Ok(next.run(req).await)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use async_std::io::Cursor;
+    use tide::http::{Body, Method, Request, Url};
+    use tide::StatusCode;
+
+    use crate::config::LimitsConfig;
+    use crate::util::api_error::{ApiError, Envelope};
+    use crate::util::limits::{check_quota, BodyLimit, RecordUsage};
+
+    #[test]
+    fn quotas_count_records_and_bytes() {
+        let limits = LimitsConfig {
+            max_body_bytes: 0,
+            max_records_per_upload: 0,
+            max_records_per_user: 10,
+            max_storage_bytes_per_user: 1000,
+        };
+        let usage = |records, bytes| RecordUsage { records, bytes };
+        assert_eq!(check_quota(&limits, usage(8, 900), usage(2, 100)), Ok(()));
+        assert_eq!(
+            check_quota(&limits, usage(8, 900), usage(3, 10)),
+            Err(ApiError::RecordQuotaExceeded(10, 8))
+        );
+        assert_eq!(
+            check_quota(&limits, usage(8, 900), usage(1, 101)),
+            Err(ApiError::StorageQuotaExceeded(1000, 900))
+        );
+        // Over the quota is refused for the account, it's not a lack of room of the server.
+        assert_eq!(
+            ApiError::RecordQuotaExceeded(10, 8).status(),
+            StatusCode::Forbidden
+        );
+        assert_eq!(
+            ApiError::StorageQuotaExceeded(1000, 900).status(),
+            StatusCode::Forbidden
+        );
+        let unlimited = LimitsConfig {
+            max_records_per_user: 0,
+            max_storage_bytes_per_user: 0,
+            ..limits
+        };
+        assert_eq!(
+            check_quota(&unlimited, usage(8, 900), usage(1000, 1000)),
+            Ok(())
+        );
+    }
+
+    async fn call(body: Body) -> StatusCode {
+        let mut app = tide::new();
+        app.with(Envelope);
+        app.with(BodyLimit::new(16));
+        app.at("/v2/upload")
+            .post(|mut req: tide::Request<()>| async move { req.body_string().await });
+        let url = Url::parse("http://localhost/v2/upload").unwrap();
+        let mut req = Request::new(Method::Post, url);
+        req.set_body(body);
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        res.status()
+    }
+
+    #[async_std::test]
+    async fn large_bodies_are_refused() {
+        assert_eq!(call(Body::from("[1, 2, 3]")).await, StatusCode::Ok);
+        assert_eq!(
+            call(Body::from("[1, 2, 3, 4, 5, 6, 7, 8]")).await,
+            StatusCode::PayloadTooLarge
+        );
+        // Without a length it's read up to the limit.
+        let chunked = |text: &'static str| Body::from_reader(Cursor::new(text), None);
+        assert_eq!(call(chunked("[1, 2, 3]")).await, StatusCode::Ok);
+        assert_eq!(
+            call(chunked("[1, 2, 3, 4, 5, 6, 7, 8]")).await,
+            StatusCode::PayloadTooLarge
+        );
+    }
+}
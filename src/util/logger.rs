@@ -0,0 +1,175 @@
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use chrono::Utc;
+use lazy_static::lazy_static;
+use log::{kv, Level, LevelFilter, Log, Metadata, Record};
+use serde_json::{json, Value as Json};
+use tide::{Middleware, Next, Request};
+
+use super::api_error::ApiError;
+
+// The value of these keys never reaches the output, whatever logs them.
+const REDACTED_KEYS: &[&str] = &[
+    "password",
+    "psd",
+    "info",
+    "email",
+    "cookie",
+    "authorization",
+    "token",
+    "secret",
+];
+
+// Every request is logged once by `AccessLog`, the access log of tide is dropped as a duplicate.
+const TIDE_ACCESS_LOG: &str = "tide::log::middleware";
+
+// Print every record as one line of json, the key-values are flattened into the object.
+struct Logger;
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level() && !metadata.target().starts_with(TIDE_ACCESS_LOG)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let mut line = format!(
+            r#"{{"time":{},"level":{},"target":{},"msg":{}"#,
+            json!(Utc::now().to_rfc3339()),
+            json!(record.level().as_str()),
+            json!(record.target()),
+            json!(record.args().to_string()),
+        );
+        let mut visitor = Fields { line: &mut line };
+        let _ = record.key_values().visit(&mut visitor);
+        line.push('}');
+
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+        let _ = writeln!(handle, "{}", line);
+    }
+
+    fn flush(&self) {
+        let _ = io::stdout().flush();
+    }
+}
+
+struct Fields<'a> {
+    line: &'a mut String,
+}
+
+impl<'kvs, 'a> kv::Visitor<'kvs> for Fields<'a> {
+    fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        let key = key.as_str();
+        let value = if is_redacted(key) {
+            json!("[REDACTED]")
+        } else if let Some(value) = value.to_i64() {
+            json!(value)
+        } else if let Some(value) = value.to_u64() {
+            json!(value)
+        } else if let Some(value) = value.to_f64() {
+            json!(value)
+        } else if let Some(value) = value.to_bool() {
+            json!(value)
+        } else {
+            json!(value.to_string())
+        };
+        self.line
+            .push_str(&format!(",{}:{}", Json::from(key), value));
+        Ok(())
+    }
+}
+
+pub fn is_redacted(key: &str) -> bool {
+    let key = key.to_ascii_lowercase();
+    REDACTED_KEYS.iter().any(|it| key.contains(it))
+}
+
+// Parse the level of the config, an unknown one falls back to info.
+pub fn start_logger(level: &str) {
+    let level = level.parse::<LevelFilter>().unwrap_or(LevelFilter::Info);
+    if log::set_boxed_logger(Box::new(Logger)).is_ok() {
+        log::set_max_level(level);
+    }
+}
+
+lazy_static! {
+    static ref STARTED: i64 = Utc::now().timestamp();
+}
+static REQUEST_COUNT: AtomicU64 = AtomicU64::new(0);
+
+// The id of a request, taken from `X-Request-Id` if the proxy has set one.
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    fn of<State>(req: &Request<State>) -> Self {
+        let id = req
+            .header("X-Request-Id")
+            .map(|it| it.last().as_str().to_string())
+            .filter(|it| !it.is_empty() && it.len() <= 64)
+            .unwrap_or_else(|| {
+                let count = REQUEST_COUNT.fetch_add(1, Ordering::Relaxed);
+                format!("{:x}-{:x}", *STARTED, count)
+            });
+        RequestId(id)
+    }
+}
+
+// Log every request with its id, uid, route, latency and the response code.
+pub struct AccessLog;
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for AccessLog {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let request_id = RequestId::of(&req);
+        req.set_ext(request_id.clone());
+        let uid = req
+            .cookie("uid")
+            .map(|it| it.value().to_string())
+            .unwrap_or_default();
+        let method = req.method().to_string();
+        let route = req.url().path().to_string();
+        let start = Instant::now();
+
+        let mut res = next.run(req).await;
+        let code = ApiError::code_of(&res);
+        let status = u16::from(res.status());
+        let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+        let level = if res.status().is_server_error() {
+            Level::Error
+        } else {
+            Level::Info
+        };
+        tide::log::log!(level, "request", {
+            request_id: request_id.0.as_str(),
+            method: method.as_str(),
+            route: route.as_str(),
+            uid: uid.as_str(),
+            status: status,
+            code: code,
+            latency_ms: latency_ms,
+        });
+        res.insert_header("X-Request-Id", request_id.0);
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::util::logger::is_redacted;
+
+    #[test]
+    fn secrets_are_redacted() {
+        for key in ["password", "new_password", "info", "email", "Authorization"] {
+            assert!(is_redacted(key), "{}", key);
+        }
+        for key in ["uid", "route", "code", "request_id"] {
+            assert!(!is_redacted(key), "{}", key);
+        }
+    }
+}
@@ -0,0 +1,175 @@
+use std::error::Error;
+use std::sync::Arc;
+
+use async_native_tls::TlsConnector;
+use async_smtp::smtp::authentication::Credentials;
+use async_smtp::{
+    ClientSecurity, ClientTlsParameters, Envelope, SendableEmail, ServerAddress, SmtpClient,
+    Transport,
+};
+use async_std::fs::OpenOptions;
+use async_std::io::WriteExt;
+use chrono::Utc;
+
+use super::token::random_token;
+use crate::config::MailConfig;
+
+pub type MailResult = Result<(), Box<dyn Error + Send + Sync>>;
+
+// A plain text mail to a single address.
+#[derive(Clone, Debug)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+impl Mail {
+    // The whole message as sent over smtp, headers first.
+    // A line break in a header value would start a header of its own, such a mail is refused.
+    pub fn to_message(&self, from: &str) -> Result<String, Box<dyn Error + Send + Sync>> {
+        for (name, value) in [("From", from), ("To", &self.to), ("Subject", &self.subject)] {
+            if value.contains(['\r', '\n']) {
+                return Err(format!("line break in the {} header", name).into());
+            }
+        }
+        Ok(format!(
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMessage-ID: <{}@{}>\r\nMIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n{}\r\n",
+            from,
+            self.to,
+            self.subject,
+            Utc::now().to_rfc2822(),
+            random_token(16),
+            from.rsplit_once('@').map_or("localhost", |(_, domain)| domain),
+            self.body.replace('\n', "\r\n"),
+        ))
+    }
+}
+
+#[tide::utils::async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, mail: Mail) -> MailResult;
+}
+
+// Sends through a smtp relay, one connection per mail.
+pub struct SmtpMailer {
+    config: MailConfig,
+}
+
+#[tide::utils::async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, mail: Mail) -> MailResult {
+        let smtp = &self.config.smtp;
+        let tls = ClientTlsParameters::new(smtp.host.clone(), TlsConnector::new());
+        let security = match smtp.security.as_str() {
+            "tls" => ClientSecurity::Wrapper(tls),
+            "none" => ClientSecurity::None,
+            _ => ClientSecurity::Required(tls),
+        };
+        let mut client =
+            SmtpClient::with_security(ServerAddress::new(smtp.host.clone(), smtp.port), security);
+        if !smtp.username.is_empty() {
+            client = client.credentials(Credentials::new(
+                smtp.username.clone(),
+                smtp.password.clone(),
+            ));
+        }
+        let envelope = Envelope::new(Some(self.config.from.parse()?), vec![mail.to.parse()?])?;
+        let email = SendableEmail::new(
+            envelope,
+            random_token(16),
+            mail.to_message(&self.config.from)?,
+        );
+        client.into_transport().send(email).await?;
+        Ok(())
+    }
+}
+
+// Appends every mail to a file, for local testing.
+pub struct FileMailer {
+    config: MailConfig,
+}
+
+#[tide::utils::async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, mail: Mail) -> MailResult {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.config.file)
+            .await?;
+        file.write_all(mail.to_message(&self.config.from)?.as_bytes())
+            .await?;
+        file.write_all(b"\r\n").await?;
+        Ok(())
+    }
+}
+
+// Prints every mail to stdout, for local testing.
+pub struct StdoutMailer {
+    config: MailConfig,
+}
+
+#[tide::utils::async_trait]
+impl Mailer for StdoutMailer {
+    async fn send(&self, mail: Mail) -> MailResult {
+        println!("{}", mail.to_message(&self.config.from)?);
+        Ok(())
+    }
+}
+
+// The mailer chosen by `transport`, stdout by default.
+pub fn new_mailer(config: &MailConfig) -> Arc<dyn Mailer> {
+    let config = config.clone();
+    match config.transport.as_str() {
+        "smtp" => Arc::new(SmtpMailer { config }),
+        "file" => Arc::new(FileMailer { config }),
+        _ => Arc::new(StdoutMailer { config }),
+    }
+}
+
+// Send in the background, a slow or broken relay must not hold the request.
+pub fn send_in_background(mailer: Arc<dyn Mailer>, mail: Mail) {
+    async_std::task::spawn(async move {
+        let email = mail.to.clone();
+        if let Err(e) = mailer.send(mail).await {
+            tide::log::error!("mail failed", { email: email.as_str(), error: e.to_string() });
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use crate::util::mailer::Mail;
+
+    #[test]
+    fn message_has_headers_then_body() {
+        let mail = Mail {
+            to: "user@example.com".to_string(),
+            subject: "Verify".to_string(),
+            body: "line 1\nline 2".to_string(),
+        };
+        let message = mail.to_message("finance@example.com").unwrap();
+        let (headers, body) = message.split_once("\r\n\r\n").unwrap();
+        assert!(headers.starts_with("From: finance@example.com\r\nTo: user@example.com\r\n"));
+        assert!(headers.contains("Message-ID: <"));
+        assert!(headers.contains("@example.com>"));
+        assert_eq!(body, "line 1\r\nline 2\r\n");
+    }
+
+    #[test]
+    fn line_breaks_in_headers_are_refused() {
+        let mail = Mail {
+            to: "x@y.com\r\nBcc: a@evil.com\r\nX: y@z.com".to_string(),
+            subject: "Verify".to_string(),
+            body: String::new(),
+        };
+        assert!(mail.to_message("finance@example.com").is_err());
+        let mail = Mail {
+            to: "user@example.com".to_string(),
+            subject: "Verify\nBcc: a@evil.com".to_string(),
+            body: String::new(),
+        };
+        assert!(mail.to_message("finance@example.com").is_err());
+    }
+}
@@ -0,0 +1,133 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::Utc;
+use futures_lite::future;
+use sqlx::{MySqlConnection, MySqlPool, Row};
+use tide::log;
+
+use super::shutdown::Shutdown;
+use crate::config::Config;
+
+// How often the background maintenance runs.
+const INTERVAL: Duration = Duration::from_secs(3600);
+
+// Every table holding data of the user, the user itself last.
+const USER_TABLES: &[&str] = &[
+    "record",
+    "password_reset",
+    "email_change",
+    "password_history",
+    "recovery_code",
+    "login_challenge",
+    "api_token",
+    "audit_event",
+    "user",
+];
+
+// Remove the user and everything derived from it.
+pub async fn purge_user(conn: &mut MySqlConnection, uid: i64) -> Result<(), sqlx::Error> {
+    for table in USER_TABLES {
+        // Kept until `[audit] retention_days` drops them: the deletion itself, what the user did
+        // as an admin, and what finance-admin did to the user, which it files under the user.
+        // The actions of the admin routes on the user are filed under the admin, they stay anyway.
+        let query = match *table {
+            "audit_event" => {
+                "delete from audit_event where uid=? and action<>'account.delete' and action not like 'admin.%'".to_string()
+            }
+            table => format!("delete from {} where uid=?", table),
+        };
+        sqlx::query(&query).bind(uid).execute(&mut *conn).await?;
+    }
+    sqlx::query("delete from login_throttle where `key`=?")
+        .bind(format!("uid:{}", uid))
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+// Purge the accounts whose grace period is over, one transaction each.
+pub async fn purge_deleted_accounts(pool: &MySqlPool) -> Result<u64, sqlx::Error> {
+    let uids: Vec<i64> = sqlx::query("select uid from user where delete_after<=?")
+        .bind(Utc::now().timestamp())
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|row| row.get::<i64, &str>("uid"))
+        .collect();
+    for uid in &uids {
+        let mut tx = pool.begin().await?;
+        purge_user(&mut tx, *uid).await?;
+        tx.commit().await?;
+        log::info!("account purged", { uid: uid });
+    }
+    Ok(uids.len() as u64)
+}
+
+// Drop the audit events older than `[audit] retention_days`.
+pub async fn prune_audit_events(pool: &MySqlPool, retention_days: u32) -> Result<u64, sqlx::Error> {
+    if retention_days == 0 {
+        return Ok(0);
+    }
+    let before = Utc::now().timestamp() - i64::from(retention_days) * 86400;
+    let pruned = sqlx::query("delete from audit_event where created_at<?")
+        .bind(before)
+        .execute(pool)
+        .await?
+        .rows_affected();
+    if pruned > 0 {
+        log::info!("audit events pruned", { count: pruned });
+    }
+    Ok(pruned)
+}
+
+// Delete the rows which can't be used anymore: expired links and challenges, revoked or expired
+// api tokens and the login throttles untouched for a day. The count deleted is given by table.
+pub async fn vacuum_expired(pool: &MySqlPool) -> Result<Vec<(&'static str, u64)>, sqlx::Error> {
+    let now = Utc::now().timestamp();
+    let queries = [
+        (
+            "password_reset",
+            "delete from password_reset where expires_at<=?",
+        ),
+        (
+            "email_change",
+            "delete from email_change where expires_at<=?",
+        ),
+        (
+            "login_challenge",
+            "delete from login_challenge where expires_at<=?",
+        ),
+        (
+            "api_token",
+            "delete from api_token where revoked_at is not null or expires_at<=?",
+        ),
+    ];
+    let mut counts = Vec::new();
+    for (table, query) in queries {
+        let deleted = sqlx::query(query).bind(now).execute(pool).await?;
+        counts.push((table, deleted.rows_affected()));
+    }
+    let throttles =
+        sqlx::query("delete from login_throttle where locked_until<=? and updated_at<=?")
+            .bind(now)
+            .bind(now - 86400)
+            .execute(pool)
+            .await?;
+    counts.push(("login_throttle", throttles.rows_affected()));
+    Ok(counts)
+}
+
+// Run the maintenance every hour, a failed round is logged and tried again the next one.
+// It stops on shutdown, a round already started is finished first.
+pub async fn run_maintenance(pool: MySqlPool, config: Arc<Config>, shutdown: Shutdown) {
+    while !shutdown.is_stopping() {
+        if let Err(e) = purge_deleted_accounts(&pool).await {
+            log::error!("account purge failed", { error: e.to_string() });
+        }
+        if let Err(e) = prune_audit_events(&pool, config.audit.retention_days).await {
+            log::error!("audit prune failed", { error: e.to_string() });
+        }
+        future::race(async_std::task::sleep(INTERVAL), shutdown.wait()).await;
+    }
+}
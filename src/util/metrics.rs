@@ -0,0 +1,220 @@
+use std::time::Instant;
+
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram, register_histogram_vec, register_int_counter_vec, register_int_gauge,
+    Encoder, Histogram, HistogramVec, IntCounterVec, IntGauge, TextEncoder,
+};
+use sqlx::MySqlPool;
+use tide::{Endpoint, Middleware, Next, Request, Response, StatusCode};
+
+use super::api_error::ApiError;
+use crate::state::State;
+
+lazy_static! {
+    pub static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "finance_http_requests_total",
+        "Requests by route and response code.",
+        &["route", "code"]
+    )
+    .unwrap();
+    pub static ref HTTP_LATENCY: HistogramVec = register_histogram_vec!(
+        "finance_http_request_duration_seconds",
+        "Request latency by route and response code.",
+        &["route", "code"]
+    )
+    .unwrap();
+    pub static ref POOL_WAIT: Histogram = register_histogram!(
+        "finance_db_pool_wait_seconds",
+        "Time spent waiting for a pooled connection."
+    )
+    .unwrap();
+    pub static ref POOL_SIZE: IntGauge = register_int_gauge!(
+        "finance_db_pool_connections",
+        "Connections currently opened by the pool."
+    )
+    .unwrap();
+    pub static ref POOL_IDLE: IntGauge = register_int_gauge!(
+        "finance_db_pool_idle_connections",
+        "Connections currently idle in the pool."
+    )
+    .unwrap();
+    // outcome: success, failure
+    pub static ref LOGINS: IntCounterVec = register_int_counter_vec!(
+        "finance_logins_total",
+        "Login attempts by outcome.",
+        &["outcome"]
+    )
+    .unwrap();
+    // operation: uploaded, deleted, downloaded
+    pub static ref RECORDS: IntCounterVec = register_int_counter_vec!(
+        "finance_records_total",
+        "Records by operation.",
+        &["operation"]
+    )
+    .unwrap();
+}
+
+// Whether the path is one of the route, a `:name` segment takes any segment.
+fn match_route(route: &str, path: &str) -> bool {
+    let (mut route, mut path) = (route.split('/'), path.split('/'));
+    loop {
+        match (route.next(), path.next()) {
+            (None, None) => return true,
+            (Some(expected), Some(segment)) if expected == segment => {}
+            (Some(expected), Some(segment)) if expected.starts_with(':') && !segment.is_empty() => {
+            }
+            _ => return false,
+        }
+    }
+}
+
+// The route label of a request, which is the route mounted and so labels the parameters by name.
+// Any other path shares one label whoever answered it, so that scanners can't blow up the series.
+fn route_label(routes: &[String], path: &str) -> String {
+    routes
+        .iter()
+        .find(|route| match_route(route, path))
+        .cloned()
+        .unwrap_or_else(|| "unmatched".to_string())
+}
+
+// Count every request and its latency, it should be the outermost middleware.
+// It takes the routes as `mount` in main has them.
+pub struct HttpMetrics {
+    routes: Vec<String>,
+}
+
+impl HttpMetrics {
+    pub fn new(routes: Vec<String>) -> Self {
+        Self { routes }
+    }
+}
+
+// Set by `HttpMetrics` so that `PoolWaitMetrics` could tell how long the pool kept the request waiting.
+#[derive(Clone, Copy)]
+struct PoolWaitStart(Instant);
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for HttpMetrics {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let path = req.url().path().to_string();
+        let start = Instant::now();
+        req.set_ext(PoolWaitStart(start));
+
+        let res = next.run(req).await;
+        let code = ApiError::code_of(&res).to_string();
+        let route = route_label(&self.routes, &path);
+        HTTP_REQUESTS.with_label_values(&[&route, &code]).inc();
+        HTTP_LATENCY
+            .with_label_values(&[&route, &code])
+            .observe(start.elapsed().as_secs_f64());
+        Ok(res)
+    }
+}
+
+// Observe the time spent in the sqlx middleware, which is the wait for a pooled connection.
+// It must be registered right after the sqlx middleware.
+pub struct PoolWaitMetrics;
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for PoolWaitMetrics {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        if let Some(PoolWaitStart(start)) = req.ext::<PoolWaitStart>() {
+            POOL_WAIT.observe(start.elapsed().as_secs_f64());
+        }
+        Ok(next.run(req).await)
+    }
+}
+
+// Serve every metric in the prometheus text format, the pool gauges are sampled on scrape.
+pub fn metrics(pool: MySqlPool) -> impl Endpoint<State> {
+    move |_req: Request<State>| {
+        let pool = pool.clone();
+        async move {
+            POOL_SIZE.set(i64::from(pool.size()));
+            POOL_IDLE.set(pool.num_idle() as i64);
+
+            let encoder = TextEncoder::new();
+            let mut buffer = vec![];
+            encoder.encode(&prometheus::gather(), &mut buffer)?;
+            Ok(Response::builder(StatusCode::Ok)
+                .content_type(encoder.format_type())
+                .body(buffer)
+                .build())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tide::http::{Method, Request, Response, Url};
+    use tide::StatusCode;
+
+    use crate::util::metrics::route_label;
+    use crate::util::prelude::*;
+
+    fn routes(routes: &[&str]) -> Vec<String> {
+        routes.iter().map(|it| it.to_string()).collect()
+    }
+
+    #[test]
+    fn parameters_share_one_label() {
+        let routes = routes(&[
+            "/tokens/:id",
+            "/v1/tokens",
+            "/v2/tokens/:id",
+            "/admin/users",
+            "/admin/users/:uid/disable",
+        ]);
+        assert_eq!(route_label(&routes, "/v2/tokens/123"), "/v2/tokens/:id");
+        assert_eq!(route_label(&routes, "/v2/tokens/456"), "/v2/tokens/:id");
+        assert_eq!(route_label(&routes, "/tokens/abc"), "/tokens/:id");
+        assert_eq!(route_label(&routes, "/v1/tokens"), "/v1/tokens");
+        assert_eq!(
+            route_label(&routes, "/admin/users/10001/disable"),
+            "/admin/users/:uid/disable"
+        );
+        assert_eq!(route_label(&routes, "/admin/users"), "/admin/users");
+        for unknown in [
+            "/tokens/",
+            "/v2/tokens/1/x",
+            "/admin/users/1",
+            "/wp-login.php",
+        ] {
+            assert_eq!(route_label(&routes, unknown), "unmatched", "{}", unknown);
+        }
+    }
+
+    async fn not_login(_req: tide::Request<()>) -> Result<tide::Response, ApiError> {
+        Err(ApiError::NotLogin)
+    }
+
+    #[async_std::test]
+    async fn requests_are_counted_by_route_and_code() {
+        let mut app = tide::new();
+        app.with(HttpMetrics::new(routes(&["/metrics-test"])));
+        app.with(Envelope);
+        app.with(BodyLimit::new(16));
+        app.at("/metrics-test").get(api(not_login));
+
+        let counter = HTTP_REQUESTS.with_label_values(&["/metrics-test", "11"]);
+        let unmatched = HTTP_REQUESTS.with_label_values(&["unmatched", "0"]);
+        let (before, unmatched_before) = (counter.get(), unmatched.get());
+        let too_large = HTTP_REQUESTS.with_label_values(&["unmatched", "31"]);
+        let too_large_before = too_large.get();
+        for path in ["/metrics-test", "/no-such-route"] {
+            let url = Url::parse("http://localhost").unwrap().join(path).unwrap();
+            let _: Response = app.respond(Request::new(Method::Get, url)).await.unwrap();
+        }
+        // Answered before the routing, an unknown path is still no label of its own.
+        let url = Url::parse("http://localhost/v2/scanned/123").unwrap();
+        let mut req = Request::new(Method::Post, url);
+        req.set_body("x".repeat(64));
+        let res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::PayloadTooLarge);
+        assert_eq!(counter.get(), before + 1);
+        assert_eq!(unmatched.get(), unmatched_before + 1);
+        assert_eq!(too_large.get(), too_large_before + 1);
+    }
+}
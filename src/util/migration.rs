@@ -0,0 +1,25 @@
+use sqlx::migrate::{MigrateError, Migrator};
+use sqlx::{MySqlPool, Row};
+
+// Every migration under `./migrations`, embedded at compile time.
+pub static MIGRATOR: Migrator = sqlx::migrate!();
+
+pub async fn migrate(pool: &MySqlPool) -> Result<(), MigrateError> {
+    MIGRATOR.run(pool).await
+}
+
+// The versions known by this build but not yet applied to the database.
+pub async fn pending_migrations(pool: &MySqlPool) -> Result<Vec<i64>, sqlx::Error> {
+    let applied: Vec<i64> = sqlx::query("select version from _sqlx_migrations where success = 1")
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|row| row.get::<i64, usize>(0))
+        .collect();
+
+    Ok(MIGRATOR
+        .iter()
+        .map(|it| it.version)
+        .filter(|it| !applied.contains(it))
+        .collect())
+}
@@ -1,6 +1,28 @@
 pub mod prelude;
 
+mod api_error;
+mod api_version;
+mod audit;
+mod backup;
 mod check_login;
+mod cors;
 mod cryp;
-mod get_json;
+mod csrf;
+mod health;
+mod limits;
+mod logger;
+mod mailer;
+mod maintenance;
+mod metrics;
+mod migration;
+mod password_history;
+mod password_policy;
+mod preferences;
+mod rate_limit;
 mod regex_check_format;
+mod request_body;
+mod shutdown;
+mod tls;
+mod token;
+mod totp;
+mod verification;
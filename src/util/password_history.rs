@@ -0,0 +1,74 @@
+use chrono::Utc;
+use sqlx::{MySqlConnection, Row};
+
+use super::api_error::ApiError;
+use super::token::hash_token;
+
+// The uid salts the hash, so that the same password of two users is never stored alike.
+fn password_hash(uid: i64, password: &str) -> String {
+    hash_token(&format!("{}|{}", uid, password))
+}
+
+// Refuse the password if it's the current one or one of the last `history` ones.
+pub async fn check_reuse(
+    conn: &mut MySqlConnection,
+    uid: i64,
+    password: &str,
+    history: u32,
+) -> Result<(), ApiError> {
+    if history == 0 {
+        return Ok(());
+    }
+    let current = sqlx::query("select psd from user where uid=?")
+        .bind(uid)
+        .fetch_optional(&mut *conn)
+        .await?
+        .map(|row| row.get::<String, &str>("psd"));
+    if current.as_deref() == Some(password) {
+        return Err(ApiError::PasswordReused);
+    }
+    let hash = password_hash(uid, password);
+    let reused =
+        sqlx::query("select psd_hash from password_history where uid=? order by id desc limit ?")
+            .bind(uid)
+            .bind(history)
+            .fetch_all(&mut *conn)
+            .await?
+            .into_iter()
+            .any(|row| row.get::<String, &str>("psd_hash") == hash);
+    if reused {
+        Err(ApiError::PasswordReused)
+    } else {
+        Ok(())
+    }
+}
+
+// Keep the password set just now, and forget the ones older than the history.
+pub async fn remember_password(
+    conn: &mut MySqlConnection,
+    uid: i64,
+    password: &str,
+    history: u32,
+) -> Result<(), ApiError> {
+    sqlx::query("insert into password_history(uid, psd_hash, changed_at) values(?, ?, ?)")
+        .bind(uid)
+        .bind(password_hash(uid, password))
+        .bind(Utc::now().timestamp())
+        .execute(&mut *conn)
+        .await?;
+    let kept = sqlx::query(
+        "select id from password_history where uid=? order by id desc limit 1 offset ?",
+    )
+    .bind(uid)
+    .bind(history.saturating_sub(1))
+    .fetch_optional(&mut *conn)
+    .await?;
+    if let Some(kept) = kept {
+        sqlx::query("delete from password_history where uid=? and id<?")
+            .bind(uid)
+            .bind(kept.get::<i64, &str>("id"))
+            .execute(&mut *conn)
+            .await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,243 @@
+use std::collections::HashSet;
+use std::{fs, io};
+
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+
+use super::api_error::ApiError;
+use crate::config::PasswordConfig;
+
+// Passwords and their parts a guesser tries first.
+const COMMON_WORDS: &[&str] = &[
+    "password", "passw0rd", "qwerty", "asdf", "zxcvbn", "123456", "letmein", "welcome", "admin",
+    "iloveyou", "monkey", "dragon", "football", "baseball", "sunshine", "princess", "master",
+    "shadow", "hunter", "trustno1", "secret", "login", "finance", "money", "abc123",
+];
+
+const CLASSES: &[&str] = &["lower", "upper", "digit", "symbol", "space", "other"];
+
+fn class_of(ch: char) -> &'static str {
+    match ch {
+        'a'..='z' => "lower",
+        'A'..='Z' => "upper",
+        '0'..='9' => "digit",
+        ' ' => "space",
+        _ if ch.is_ascii_punctuation() => "symbol",
+        _ => "other",
+    }
+}
+
+// The characters a guesser has to try for a class.
+fn class_size(class: &str) -> u32 {
+    match class {
+        "lower" | "upper" => 26,
+        "digit" => 10,
+        "symbol" => 33,
+        "space" => 1,
+        _ => 100,
+    }
+}
+
+// A rough estimate in the manner of zxcvbn, from 0 (guessed at once) to 4 (very unlikely guessed).
+// Common words and the user's own names cost a few guesses each, so do repeats and runs like
+// `aaa` or `1234`, every other character costs the size of the classes in use.
+pub fn estimate_score(password: &str, user_inputs: &[&str]) -> u8 {
+    let mut classes: Vec<&str> = password.chars().map(class_of).collect();
+    classes.sort_unstable();
+    classes.dedup();
+    let per_char = f64::from(classes.iter().map(|it| class_size(it)).sum::<u32>().max(1)).log2();
+
+    let mut text = password.to_lowercase();
+    let mut bits = 0.0;
+    let user_inputs: Vec<String> = user_inputs
+        .iter()
+        .map(|it| it.to_lowercase())
+        .filter(|it| it.chars().count() >= 3)
+        .collect();
+    let words = COMMON_WORDS
+        .iter()
+        .copied()
+        .chain(user_inputs.iter().map(String::as_str));
+    for word in words {
+        while let Some(at) = text.find(word) {
+            text.replace_range(at..at + word.len(), "\0");
+            bits += 8.0;
+        }
+    }
+
+    let mut last: Option<char> = None;
+    for ch in text.chars() {
+        if ch == '\0' {
+            last = None;
+            continue;
+        }
+        let cheap = last.is_some_and(|last| {
+            let (last, ch) = (last as u32, ch as u32);
+            last == ch || last + 1 == ch || ch + 1 == last
+        });
+        bits += if cheap { 1.0 } else { per_char };
+        last = Some(ch);
+    }
+
+    // The thresholds of zxcvbn, in log10 of the guesses.
+    match bits * 2f64.log10() {
+        it if it < 3.0 => 0,
+        it if it < 6.0 => 1,
+        it if it < 8.0 => 2,
+        it if it < 10.0 => 3,
+        _ => 4,
+    }
+}
+
+fn sha1_hex(text: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.input_str(text);
+    hasher.result_str().to_uppercase()
+}
+
+// The lines of a breached list, the pwned passwords `HASH:count` lines are kept as the hash.
+fn parse_breached(text: &str) -> HashSet<String> {
+    text.lines()
+        .map(|line| line.trim_end_matches('\r'))
+        .filter(|line| !line.is_empty())
+        .map(|line| match line.split_once(':') {
+            Some((hash, _))
+                if hash.len() == 40 && hash.bytes().all(|it| it.is_ascii_hexdigit()) =>
+            {
+                hash.to_uppercase()
+            }
+            _ => line.to_string(),
+        })
+        .collect()
+}
+
+// The rules of `[password]` a new password has to pass, the breached list is read once on start.
+pub struct PasswordPolicy {
+    config: PasswordConfig,
+    breached: HashSet<String>,
+}
+
+impl PasswordPolicy {
+    pub fn new(config: &PasswordConfig) -> io::Result<Self> {
+        for class in config
+            .allowed_classes
+            .iter()
+            .chain(&config.required_classes)
+        {
+            if !CLASSES.contains(&class.as_str()) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("password: unknown character class {}", class),
+                ));
+            }
+        }
+        let breached = match config.breached_file.as_str() {
+            "" => HashSet::new(),
+            path => parse_breached(
+                &fs::read_to_string(path)
+                    .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?,
+            ),
+        };
+        Ok(Self {
+            config: config.clone(),
+            breached,
+        })
+    }
+
+    fn is_breached(&self, password: &str) -> bool {
+        if self.breached.is_empty() {
+            return false;
+        }
+        self.breached.contains(password) || self.breached.contains(&sha1_hex(password))
+    }
+
+    // Every reason the password is refused for, `user_inputs` are the names it must not be made of.
+    pub fn reasons(&self, password: &str, user_inputs: &[&str]) -> Vec<String> {
+        let config = &self.config;
+        let mut reasons = vec![];
+        let length = password.chars().count();
+        if length < config.min_length {
+            reasons.push("too_short".to_string());
+        }
+        if length > config.max_length {
+            reasons.push("too_long".to_string());
+        }
+        if password
+            .chars()
+            .any(|ch| !config.allowed_classes.iter().any(|it| it == class_of(ch)))
+        {
+            reasons.push("disallowed_character".to_string());
+        }
+        for class in &config.required_classes {
+            if !password.chars().any(|ch| class_of(ch) == class) {
+                reasons.push(format!("missing_{}", class));
+            }
+        }
+        if estimate_score(password, user_inputs) < config.min_score {
+            reasons.push("too_guessable".to_string());
+        }
+        if self.is_breached(password) {
+            reasons.push("breached".to_string());
+        }
+        reasons
+    }
+
+    pub fn check(&self, password: &str, user_inputs: &[&str]) -> Result<(), ApiError> {
+        match self.reasons(password, user_inputs) {
+            reasons if reasons.is_empty() => Ok(()),
+            reasons => Err(ApiError::WeakPassword(reasons)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::PasswordConfig;
+    use crate::util::password_policy::{estimate_score, parse_breached, sha1_hex, PasswordPolicy};
+
+    #[test]
+    fn scores_follow_the_guesses() {
+        assert_eq!(estimate_score("password", &[]), 0);
+        assert!(estimate_score("aaaaaaaa", &[]) < 2);
+        assert!(estimate_score("abc12345", &[]) < 2);
+        assert!(estimate_score("alice2024", &["alice"]) < estimate_score("alice2024", &[]));
+        assert_eq!(estimate_score("correct horse battery staple", &[]), 4);
+        assert_eq!(estimate_score("Tr0ub4dor&3", &[]), 4);
+    }
+
+    #[test]
+    fn every_reason_is_reported() {
+        let policy = PasswordPolicy::new(&PasswordConfig {
+            allowed_classes: vec!["lower".to_string(), "digit".to_string()],
+            required_classes: vec!["digit".to_string()],
+            ..PasswordConfig::default()
+        })
+        .unwrap();
+        assert_eq!(
+            policy.reasons("Pass", &[]),
+            [
+                "too_short",
+                "disallowed_character",
+                "missing_digit",
+                "too_guessable"
+            ]
+        );
+        assert!(policy.check("violet7crane4harbor", &[]).is_ok());
+        assert!(policy.check(&"a".repeat(129), &[]).is_err());
+
+        let policy = PasswordPolicy {
+            breached: parse_breached(&format!(
+                "hunter42moon\r\n{}:52\n",
+                sha1_hex("violet7crane4harbor").to_lowercase()
+            )),
+            ..policy
+        };
+        assert_eq!(policy.reasons("hunter42moon", &[]), ["breached"]);
+        assert_eq!(policy.reasons("violet7crane4harbor", &[]), ["breached"]);
+        assert!(PasswordPolicy::new(&PasswordConfig {
+            required_classes: vec!["emoji".to_string()],
+            ..PasswordConfig::default()
+        })
+        .is_err());
+    }
+}
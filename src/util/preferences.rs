@@ -0,0 +1,222 @@
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, TimeZone};
+use chrono_tz::Tz;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::Serialize;
+use sqlx::{MySqlConnection, Row};
+
+// The profile of the user, and the preferences every date and period of it is read by.
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct Preferences {
+    pub display_name: String,
+    pub base_currency: String,
+    pub timezone: String,
+    pub locale: String,
+    pub first_day_of_week: u8,
+    pub month_start_day: u8,
+    // When the profile was changed last, for the clients to sync by.
+    pub updated_at: i64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Period {
+    Week,
+    Month,
+}
+
+pub fn match_display_name(text: &str) -> bool {
+    text.chars().count() <= 64 && !text.chars().any(char::is_control)
+}
+
+// An iso 4217 code.
+pub fn match_currency(text: &str) -> bool {
+    text.len() == 3 && text.bytes().all(|it| it.is_ascii_uppercase())
+}
+
+// A name of the iana time zone database, like `Asia/Shanghai`.
+pub fn match_timezone(text: &str) -> bool {
+    text.parse::<Tz>().is_ok()
+}
+
+// A bcp 47 language tag, like `zh-CN`.
+pub fn match_locale(text: &str) -> bool {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"^[a-z]{2,3}(-[A-Za-z0-9]{2,8})*$").unwrap();
+    }
+    text.len() <= 35 && RE.is_match(text)
+}
+
+impl Preferences {
+    pub async fn load(conn: &mut MySqlConnection, uid: i64) -> Result<Self, sqlx::Error> {
+        let row = sqlx::query(
+            "select display_name, base_currency, timezone, locale, first_day_of_week, month_start_day, profile_updated_at from user where uid=?",
+        )
+        .bind(uid)
+        .fetch_one(conn)
+        .await?;
+        Ok(Self {
+            display_name: row.get("display_name"),
+            base_currency: row.get("base_currency"),
+            timezone: row.get("timezone"),
+            locale: row.get("locale"),
+            first_day_of_week: row.get("first_day_of_week"),
+            month_start_day: row.get("month_start_day"),
+            updated_at: row.get("profile_updated_at"),
+        })
+    }
+
+    fn tz(&self) -> Tz {
+        self.timezone.parse().unwrap_or(Tz::UTC)
+    }
+
+    // The day a record's date falls on for the user. A plain date is taken as it is,
+    // a time with an offset or an epoch in seconds or milliseconds is moved into the user's time zone.
+    // Eight digits are a yyyymmdd date, fewer are neither, an epoch takes more.
+    pub fn parse_date(&self, text: &str) -> Option<NaiveDate> {
+        let text = text.trim();
+        let tz = self.tz();
+        if !text.is_empty() && text.bytes().all(|it| it.is_ascii_digit()) {
+            match text.len() {
+                0..=7 => return None,
+                8 => return NaiveDate::parse_from_str(text, "%Y%m%d").ok(),
+                _ => {}
+            }
+            let epoch = text.parse::<i64>().ok()?;
+            let secs = if text.len() > 10 { epoch / 1000 } else { epoch };
+            return Some(tz.timestamp_opt(secs, 0).single()?.naive_local().date());
+        }
+        if let Ok(time) = DateTime::parse_from_rfc3339(text) {
+            return Some(time.with_timezone(&tz).naive_local().date());
+        }
+        for format in ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M"] {
+            if let Ok(time) = NaiveDateTime::parse_from_str(text, format) {
+                return Some(time.date());
+            }
+        }
+        ["%Y-%m-%d", "%Y/%m/%d"]
+            .iter()
+            .find_map(|format| NaiveDate::parse_from_str(text, format).ok())
+    }
+
+    // The first day of the week or fiscal month the date belongs to.
+    pub fn period_start(&self, date: NaiveDate, period: Period) -> NaiveDate {
+        match period {
+            Period::Week => {
+                let first = u32::from(self.first_day_of_week.clamp(1, 7)) - 1;
+                let back = (date.weekday().num_days_from_monday() + 7 - first) % 7;
+                date - Duration::days(i64::from(back))
+            }
+            Period::Month => {
+                let day = u32::from(self.month_start_day.clamp(1, 28));
+                let (year, month) = match date.day() >= day {
+                    true => (date.year(), date.month()),
+                    false if date.month() == 1 => (date.year() - 1, 12),
+                    false => (date.year(), date.month() - 1),
+                };
+                NaiveDate::from_ymd(year, month, day)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::NaiveDate;
+
+    use crate::util::preferences::{
+        match_currency, match_locale, match_timezone, Period, Preferences,
+    };
+
+    fn preferences(timezone: &str, first_day_of_week: u8, month_start_day: u8) -> Preferences {
+        Preferences {
+            display_name: String::new(),
+            base_currency: "CNY".to_string(),
+            timezone: timezone.to_string(),
+            locale: "zh-CN".to_string(),
+            first_day_of_week,
+            month_start_day,
+            updated_at: 0,
+        }
+    }
+
+    #[test]
+    fn dates_are_read_in_the_user_time_zone() {
+        let shanghai = preferences("Asia/Shanghai", 1, 1);
+        let day = |y, m, d| Some(NaiveDate::from_ymd(y, m, d));
+        assert_eq!(shanghai.parse_date("2026-10-19"), day(2026, 10, 19));
+        assert_eq!(shanghai.parse_date("2026/10/19"), day(2026, 10, 19));
+        assert_eq!(
+            shanghai.parse_date("2026-10-19 23:30:00"),
+            day(2026, 10, 19)
+        );
+        // 20:00 utc is already the next day in shanghai.
+        assert_eq!(
+            shanghai.parse_date("2026-10-19T20:00:00Z"),
+            day(2026, 10, 20)
+        );
+        assert_eq!(shanghai.parse_date("1792440000"), day(2026, 10, 20));
+        assert_eq!(shanghai.parse_date("1792440000000"), day(2026, 10, 20));
+        let utc = preferences("UTC", 1, 1);
+        assert_eq!(utc.parse_date("1792440000"), day(2026, 10, 19));
+        // Not the epoch of 1970-08-23.
+        assert_eq!(utc.parse_date("20261019"), day(2026, 10, 19));
+        for broken in [
+            "",
+            "yesterday",
+            "2026-13-01",
+            "19/10/2026",
+            "20261319",
+            "2026101",
+            "86400",
+        ] {
+            assert_eq!(utc.parse_date(broken), None, "{}", broken);
+        }
+    }
+
+    #[test]
+    fn periods_start_on_the_preferred_day() {
+        let date = |y, m, d| NaiveDate::from_ymd(y, m, d);
+        // 2026-10-21 is a wednesday.
+        let monday = preferences("UTC", 1, 1);
+        assert_eq!(
+            monday.period_start(date(2026, 10, 21), Period::Week),
+            date(2026, 10, 19)
+        );
+        let sunday = preferences("UTC", 7, 1);
+        assert_eq!(
+            sunday.period_start(date(2026, 10, 21), Period::Week),
+            date(2026, 10, 18)
+        );
+        assert_eq!(
+            sunday.period_start(date(2026, 10, 18), Period::Week),
+            date(2026, 10, 18)
+        );
+
+        assert_eq!(
+            monday.period_start(date(2026, 10, 21), Period::Month),
+            date(2026, 10, 1)
+        );
+        let payday = preferences("UTC", 1, 25);
+        assert_eq!(
+            payday.period_start(date(2026, 10, 21), Period::Month),
+            date(2026, 9, 25)
+        );
+        assert_eq!(
+            payday.period_start(date(2026, 10, 25), Period::Month),
+            date(2026, 10, 25)
+        );
+        assert_eq!(
+            payday.period_start(date(2026, 1, 3), Period::Month),
+            date(2025, 12, 25)
+        );
+    }
+
+    #[test]
+    fn profile_formats() {
+        assert!(match_currency("CNY") && !match_currency("cny") && !match_currency("CNYX"));
+        assert!(match_timezone("Asia/Shanghai") && match_timezone("UTC"));
+        assert!(!match_timezone("Mars/Olympus"));
+        assert!(match_locale("zh-CN") && match_locale("en") && match_locale("zh-Hans-CN"));
+        assert!(!match_locale("") && !match_locale("zh_CN") && !match_locale("ZH"));
+    }
+}
@@ -1,4 +1,26 @@
+pub use super::api_error::*;
+pub use super::api_version::*;
+pub use super::audit::*;
+pub use super::backup::*;
 pub use super::check_login::*;
+pub use super::cors::*;
 pub use super::cryp::*;
-pub use super::get_json::*;
+pub use super::csrf::*;
+pub use super::health::*;
+pub use super::limits::*;
+pub use super::logger::*;
+pub use super::mailer::*;
+pub use super::maintenance::*;
+pub use super::metrics::*;
+pub use super::migration::*;
+pub use super::password_history::*;
+pub use super::password_policy::*;
+pub use super::preferences::*;
+pub use super::rate_limit::*;
 pub use super::regex_check_format::*;
+pub use super::request_body::*;
+pub use super::shutdown::*;
+pub use super::tls::*;
+pub use super::token::*;
+pub use super::totp::*;
+pub use super::verification::*;
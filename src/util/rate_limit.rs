@@ -0,0 +1,312 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+use chrono::Utc;
+use sqlx::{MySqlPool, Row};
+use tide::Request;
+
+use crate::config::RateLimitConfig;
+
+// The throttle state of one key, e.g. `ip:127.0.0.1` or `uid:12345`.
+// Times are unix timestamps in seconds.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Bucket {
+    pub tokens: f64,
+    pub updated_at: i64,
+    pub failures: u32,
+    pub locked_until: i64,
+}
+
+impl Bucket {
+    pub fn new(policy: &RateLimitConfig, now: i64) -> Self {
+        Self {
+            tokens: f64::from(policy.burst),
+            updated_at: now,
+            failures: 0,
+            locked_until: 0,
+        }
+    }
+
+    // Take one token for an attempt, or return the seconds to wait.
+    pub fn take(&mut self, policy: &RateLimitConfig, now: i64) -> Result<(), u64> {
+        if self.locked_until > now {
+            return Err((self.locked_until - now) as u64);
+        }
+        let refill = (now - self.updated_at).max(0) as f64 * f64::from(policy.per_minute) / 60.0;
+        self.tokens = (self.tokens + refill).min(f64::from(policy.burst));
+        self.updated_at = now;
+        if self.tokens < 1.0 {
+            let wait = (1.0 - self.tokens) * 60.0 / f64::from(policy.per_minute.max(1));
+            return Err(wait.ceil() as u64);
+        }
+        self.tokens -= 1.0;
+        Ok(())
+    }
+
+    // From `max_failures` on, every failure locks the key twice as long as the last one.
+    pub fn fail(&mut self, policy: &RateLimitConfig, now: i64) {
+        self.failures += 1;
+        if self.failures >= policy.max_failures {
+            let exponent = (self.failures - policy.max_failures).min(30);
+            let lockout = policy
+                .lockout_secs
+                .saturating_mul(1u64 << exponent)
+                .min(policy.max_lockout_secs);
+            self.locked_until = now + lockout as i64;
+        }
+    }
+
+    pub fn succeed(&mut self) {
+        self.failures = 0;
+        self.locked_until = 0;
+    }
+
+    pub fn apply(&mut self, action: Action, policy: &RateLimitConfig, now: i64) -> Result<(), u64> {
+        match action {
+            Action::Take => return self.take(policy, now),
+            Action::Fail => self.fail(policy, now),
+            Action::Succeed => self.succeed(),
+        }
+        Ok(())
+    }
+
+    // A bucket which is full again and holds no failure carries nothing worth keeping.
+    fn is_idle(&self, policy: &RateLimitConfig, now: i64) -> bool {
+        let full_after =
+            (f64::from(policy.burst) - self.tokens) * 60.0 / f64::from(policy.per_minute.max(1));
+        self.failures == 0
+            && self.locked_until <= now
+            && (now - self.updated_at) as f64 >= full_after
+    }
+}
+
+// What an attempt does to the bucket of a key.
+#[derive(Clone, Copy, Debug)]
+pub enum Action {
+    Take,
+    Fail,
+    Succeed,
+}
+
+// Where the buckets are kept, `update` must apply the action atomically.
+#[tide::utils::async_trait]
+pub trait LimitStore: Send + Sync {
+    async fn update(&self, key: &str, action: Action) -> Result<(), u64>;
+}
+
+// Buckets kept in the memory of this very instance.
+pub struct MemoryStore {
+    policy: RateLimitConfig,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl MemoryStore {
+    // Buckets are pruned once there are more of them than this.
+    const PRUNE_ABOVE: usize = 10_000;
+
+    pub fn new(policy: RateLimitConfig) -> Self {
+        Self {
+            policy,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[tide::utils::async_trait]
+impl LimitStore for MemoryStore {
+    async fn update(&self, key: &str, action: Action) -> Result<(), u64> {
+        let now = Utc::now().timestamp();
+        let mut buckets = match self.buckets.lock() {
+            Ok(buckets) => buckets,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if buckets.len() > Self::PRUNE_ABOVE {
+            let policy = &self.policy;
+            buckets.retain(|_, bucket| !bucket.is_idle(policy, now));
+        }
+        let bucket = buckets
+            .entry(key.to_string())
+            .or_insert_with(|| Bucket::new(&self.policy, now));
+        bucket.apply(action, &self.policy, now)
+    }
+}
+
+// Buckets kept in the table `login_throttle`, shared by every instance on the same database.
+pub struct DatabaseStore {
+    policy: RateLimitConfig,
+    pool: MySqlPool,
+}
+
+impl DatabaseStore {
+    pub fn new(policy: RateLimitConfig, pool: MySqlPool) -> Self {
+        Self { policy, pool }
+    }
+
+    async fn update_row(&self, key: &str, action: Action) -> Result<Result<(), u64>, sqlx::Error> {
+        let now = Utc::now().timestamp();
+        let mut tx = self.pool.begin().await?;
+        let row = sqlx::query(
+            "select tokens, updated_at, failures, locked_until from login_throttle where `key`=? for update",
+        )
+        .bind(key)
+        .fetch_optional(&mut tx)
+        .await?;
+        let mut bucket = match row {
+            Some(row) => Bucket {
+                tokens: row.get("tokens"),
+                updated_at: row.get("updated_at"),
+                failures: row.get("failures"),
+                locked_until: row.get("locked_until"),
+            },
+            None => Bucket::new(&self.policy, now),
+        };
+        let res = bucket.apply(action, &self.policy, now);
+        sqlx::query(
+            "insert into login_throttle(`key`, tokens, updated_at, failures, locked_until) values(?, ?, ?, ?, ?)
+            on duplicate key update tokens=values(tokens), updated_at=values(updated_at),
+            failures=values(failures), locked_until=values(locked_until)",
+        )
+        .bind(key)
+        .bind(bucket.tokens)
+        .bind(bucket.updated_at)
+        .bind(bucket.failures)
+        .bind(bucket.locked_until)
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+        Ok(res)
+    }
+}
+
+#[tide::utils::async_trait]
+impl LimitStore for DatabaseStore {
+    async fn update(&self, key: &str, action: Action) -> Result<(), u64> {
+        // The throttle must not lock everybody out when the database hiccups, so errors let the attempt through.
+        match self.update_row(key, action).await {
+            Ok(res) => res,
+            Err(e) => {
+                tide::log::error!("login throttle failed", { key: key, error: e.to_string() });
+                Ok(())
+            }
+        }
+    }
+}
+
+// The peer address, or the address forwarded by the proxy when it's trusted.
+pub fn client_ip<State>(req: &Request<State>, trust_proxy: bool) -> String {
+    let addr = if trust_proxy {
+        req.remote()
+    } else {
+        req.peer_addr()
+    };
+    let addr = addr.unwrap_or("unknown");
+    // Strip the port, a bare ip (v4 or v6) is kept as it is.
+    match addr.parse::<IpAddr>() {
+        Ok(ip) => ip.to_string(),
+        Err(_) => addr
+            .rsplit_once(':')
+            .map_or(addr, |(ip, _)| ip)
+            .trim_matches(|it| it == '[' || it == ']')
+            .to_string(),
+    }
+}
+
+// Throttle the login attempts by ip and by uid.
+pub struct LoginLimiter {
+    policy: RateLimitConfig,
+    store: Box<dyn LimitStore>,
+}
+
+impl LoginLimiter {
+    pub fn new(policy: RateLimitConfig, pool: MySqlPool) -> Self {
+        let store: Box<dyn LimitStore> = if policy.store == "database" {
+            Box::new(DatabaseStore::new(policy.clone(), pool))
+        } else {
+            Box::new(MemoryStore::new(policy.clone()))
+        };
+        Self { policy, store }
+    }
+
+    pub fn keys<State>(&self, req: &Request<State>, uid: i64) -> Vec<String> {
+        vec![
+            format!("ip:{}", client_ip(req, self.policy.trust_proxy)),
+            format!("uid:{}", uid),
+        ]
+    }
+
+    // Every key takes a token, the longest wait is returned if any key is throttled.
+    pub async fn check(&self, keys: &[String]) -> Result<(), u64> {
+        let mut wait = 0;
+        for key in keys {
+            if let Err(secs) = self.store.update(key, Action::Take).await {
+                wait = wait.max(secs.max(1));
+            }
+        }
+        if wait > 0 {
+            Err(wait)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub async fn fail(&self, keys: &[String]) {
+        for key in keys {
+            let _ = self.store.update(key, Action::Fail).await;
+        }
+    }
+
+    pub async fn succeed(&self, keys: &[String]) {
+        for key in keys {
+            let _ = self.store.update(key, Action::Succeed).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::RateLimitConfig;
+    use crate::util::rate_limit::Bucket;
+
+    fn policy() -> RateLimitConfig {
+        RateLimitConfig {
+            burst: 3,
+            per_minute: 6,
+            max_failures: 2,
+            lockout_secs: 60,
+            max_lockout_secs: 300,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn tokens_refill_over_time() {
+        let policy = policy();
+        let mut bucket = Bucket::new(&policy, 0);
+        for _ in 0..3 {
+            assert_eq!(bucket.take(&policy, 0), Ok(()));
+        }
+        // 6 per minute, one token every 10 seconds.
+        assert_eq!(bucket.take(&policy, 0), Err(10));
+        assert_eq!(bucket.take(&policy, 10), Ok(()));
+    }
+
+    #[test]
+    fn lockout_grows_exponentially_and_is_capped() {
+        let policy = policy();
+        let mut bucket = Bucket::new(&policy, 0);
+        bucket.fail(&policy, 0);
+        assert_eq!(bucket.locked_until, 0);
+        bucket.fail(&policy, 0);
+        assert_eq!(bucket.take(&policy, 0), Err(60));
+        bucket.fail(&policy, 100);
+        assert_eq!(bucket.locked_until, 220);
+        for _ in 0..10 {
+            bucket.fail(&policy, 1000);
+        }
+        assert_eq!(bucket.locked_until, 1300);
+
+        bucket.succeed();
+        assert_eq!(bucket.take(&policy, 1000), Ok(()));
+    }
+}
@@ -1,23 +1,152 @@
 use lazy_static::lazy_static;
 use regex::Regex;
+use sqlx::{MySqlConnection, Row};
+
+use crate::config::AccountConfig;
 
 pub fn match_email(text: &str) -> bool {
     lazy_static! {
-        static ref RE: Regex = Regex::new(r"[^@\s]+@([[:word:]]+\.)+[[:word:]]+$").unwrap();
+        static ref RE: Regex = Regex::new(r"^[^@\s]+@([[:word:]]+\.)+[[:word:]]+$").unwrap();
     }
     RE.is_match(text)
 }
 
-pub fn match_id(text: &str) -> bool {
-    lazy_static! {
-        static ref RE: Regex = Regex::new(r"^\d{5,12}$").unwrap();
+// A name given to log in, told apart by its format.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoginName {
+    Uid(i64),
+    Username(String),
+    Email(String),
+}
+
+// Where the uids assigned by the server start, as the `login_names` migration found the uids taken.
+pub async fn first_assigned_uid(conn: &mut MySqlConnection) -> Result<i64, sqlx::Error> {
+    Ok(sqlx::query("select first_assigned from uid_range")
+        .fetch_one(conn)
+        .await?
+        .get::<i64, usize>(0))
+}
+
+// The uid and username formats of `[account]`, compiled once on start.
+pub struct LoginNames {
+    uid: Regex,
+    username: Regex,
+    accepted: Vec<String>,
+    first_assigned: i64,
+}
+
+impl LoginNames {
+    pub fn new(config: &AccountConfig) -> Result<Self, regex::Error> {
+        // Anchored here, so that the config can't accept a name by a part of it.
+        let whole = |pattern: &str| Regex::new(&format!("^(?:{})$", pattern));
+        Ok(Self {
+            uid: whole(&config.uid_format)?,
+            username: whole(&config.username_format)?,
+            accepted: config.login_names.clone(),
+            first_assigned: i64::MAX,
+        })
+    }
+
+    // Tell the uids from `first_assigned_uid` on apart as assigned, none are until this is set.
+    pub fn assigned_from(mut self, first_assigned: i64) -> Self {
+        self.first_assigned = first_assigned;
+        self
+    }
+
+    pub fn is_assigned(&self, uid: i64) -> bool {
+        uid >= self.first_assigned
+    }
+
+    // The assigned uids log in whatever the uid format, it's only held to the chosen ones.
+    pub fn match_uid(&self, text: &str) -> bool {
+        match text.parse::<i64>() {
+            Ok(uid) => self.uid.is_match(text) || self.is_assigned(uid),
+            Err(_) => false,
+        }
+    }
+
+    // A chosen uid stays below the assigned ones, or else the counter would be moved past it.
+    pub fn match_chosen_uid(&self, uid: i64) -> bool {
+        self.uid.is_match(&uid.to_string()) && !self.is_assigned(uid)
+    }
+
+    // An email could never be told apart from a username holding an `@`.
+    pub fn match_username(&self, text: &str) -> bool {
+        self.username.is_match(text) && !text.contains('@') && !self.match_uid(text)
+    }
+
+    pub fn accepts(&self, kind: &str) -> bool {
+        self.accepted.iter().any(|it| it == kind)
+    }
+
+    // A uid is tried first, so that the numeric logins of the old clients keep working.
+    pub fn parse(&self, text: &str) -> Option<LoginName> {
+        if self.accepts("uid") && self.match_uid(text) {
+            text.parse().ok().map(LoginName::Uid)
+        } else if self.accepts("email") && text.contains('@') && match_email(text) {
+            Some(LoginName::Email(text.to_string()))
+        } else if self.accepts("username") && self.match_username(text) {
+            Some(LoginName::Username(text.to_string()))
+        } else {
+            None
+        }
     }
-    RE.is_match(text)
 }
 
-pub fn match_password(text: &str) -> bool {
-    lazy_static! {
-        static ref RE: Regex = Regex::new(r"^[[:alnum:]]{7,15}$").unwrap();
+#[cfg(test)]
+mod test {
+    use crate::config::AccountConfig;
+    use crate::util::regex_check_format::{match_email, LoginName, LoginNames};
+
+    #[test]
+    fn emails_are_matched_whole() {
+        assert!(match_email("user@example.com"));
+        for broken in [
+            "user@",
+            "user example@example.com",
+            "x@y.com\r\nBcc: a@evil.com\r\nX: y@z.com",
+            "x@y.com\nBcc: a@evil.com",
+        ] {
+            assert!(!match_email(broken), "{}", broken);
+        }
+    }
+
+    #[test]
+    fn login_names_by_format() {
+        let names = LoginNames::new(&AccountConfig::default()).unwrap();
+        assert_eq!(names.parse("12345"), Some(LoginName::Uid(12345)));
+        assert_eq!(
+            names.parse("alice"),
+            Some(LoginName::Username("alice".to_string()))
+        );
+        assert_eq!(
+            names.parse("a@b.c"),
+            Some(LoginName::Email("a@b.c".to_string()))
+        );
+        for broken in ["", "1234", "1234567890123", "al", "alice bob", "x12345@"] {
+            assert_eq!(names.parse(broken), None, "{}", broken);
+        }
+
+        // The assigned uids are longer than the old clients could choose, they still log in.
+        let names = names.assigned_from(1_000_000_000_000);
+        assert_eq!(
+            names.parse("1000000000001"),
+            Some(LoginName::Uid(1_000_000_000_001))
+        );
+        assert!(names.match_chosen_uid(999_999_999_999));
+        assert!(!names.match_chosen_uid(1_000_000_000_000));
+
+        let names = LoginNames::new(&AccountConfig {
+            login_names: vec!["username".to_string()],
+            ..AccountConfig::default()
+        })
+        .unwrap();
+        assert_eq!(names.parse("12345"), None);
+        assert_eq!(names.parse("a@b.c"), None);
+        assert!(LoginNames::new(&AccountConfig {
+            uid_format: "(".to_string(),
+            ..AccountConfig::default()
+        })
+        .is_err());
     }
-    RE.is_match(text)
 }
@@ -0,0 +1,206 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer};
+use serde_json::Value as Json;
+use tide::Request;
+
+use super::api_error::{ApiError, FieldError};
+use crate::state::State;
+
+// A request body which knows how to check its own fields.
+pub trait Validate {
+    fn validate(&self) -> Result<(), ApiError> {
+        Ok(())
+    }
+
+    // For the rules which depend on the config as well, like the uid format, `body` checks this one.
+    fn validate_with(&self, _state: &State) -> Result<(), ApiError> {
+        self.validate()
+    }
+}
+
+// Collect the failed fields one by one so that all of them are reported together.
+#[derive(Default)]
+pub struct Rules {
+    errors: Vec<FieldError>,
+}
+
+impl Rules {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn check(mut self, field: &'static str, ok: bool, error: ApiError) -> Self {
+        if !ok {
+            self.errors.push(FieldError { field, error });
+        }
+        self
+    }
+
+    // For the rules which tell their own error, like the password policy.
+    pub fn check_result(mut self, field: &'static str, result: Result<(), ApiError>) -> Self {
+        if let Err(error) = result {
+            self.errors.push(FieldError { field, error });
+        }
+        self
+    }
+
+    pub fn finish(self) -> Result<(), ApiError> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ApiError::Fields(self.errors))
+        }
+    }
+}
+
+// Read the body as `T` and validate it.
+// A missing or non-object body is reported as POST DATA NOT EXISTS.
+pub async fn body<T>(req: &mut Request<State>) -> Result<T, ApiError>
+where
+    T: DeserializeOwned + Validate,
+{
+    let body_json: T = req
+        .body_json()
+        .await
+        .map_err(|_| ApiError::PostDataNotExists)?;
+    body_json.validate_with(req.state())?;
+    Ok(body_json)
+}
+
+// Fields with a wrong json type fall back to the default value, which is then refused by the rules.
+// So that a numeric password is a format error instead of a broken body.
+pub fn lenient<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: DeserializeOwned + Default,
+{
+    let value = Json::deserialize(deserializer)?;
+    Ok(serde_json::from_value(value).unwrap_or_default())
+}
+
+// An optional field, a wrong json type gives the default value to be refused by the rules.
+// Null is taken as left out.
+pub fn lenient_option<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: DeserializeOwned + Default,
+{
+    let value = Json::deserialize(deserializer)?;
+    if value.is_null() {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_value(value).unwrap_or_default()))
+}
+
+// An id field as sent, only one left out may be given a default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IdField {
+    // Left out or null.
+    #[default]
+    Missing,
+    Given(i64),
+    // Of a wrong type or not a whole number, for the rules to refuse.
+    Invalid,
+}
+
+impl IdField {
+    pub fn given(self) -> Option<i64> {
+        match self {
+            IdField::Given(id) => Some(id),
+            _ => None,
+        }
+    }
+}
+
+// The uid is accepted both as a number and as a string of digits.
+pub fn lenient_id<'de, D>(deserializer: D) -> Result<IdField, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = Json::deserialize(deserializer)?;
+    let id = match value {
+        Json::Null => return Ok(IdField::Missing),
+        Json::Number(number) => number.as_i64(),
+        Json::String(text) => text.parse::<i64>().ok(),
+        _ => None,
+    };
+    Ok(id.map_or(IdField::Invalid, IdField::Given))
+}
+
+// A login name, the numeric uid of the old clients is taken as its digits.
+pub fn lenient_name<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = Json::deserialize(deserializer)?;
+    let name = match value {
+        Json::Number(number) => number.to_string(),
+        Json::String(text) => text,
+        _ => String::new(),
+    };
+    Ok(name)
+}
+
+#[cfg(test)]
+mod test {
+    use serde::Deserialize;
+
+    use crate::util::prelude::*;
+
+    #[derive(Deserialize)]
+    struct Body {
+        #[serde(default, deserialize_with = "lenient_id")]
+        uid: IdField,
+        #[serde(default, deserialize_with = "lenient")]
+        password: String,
+        #[serde(default, deserialize_with = "lenient_name")]
+        login: String,
+    }
+
+    impl Validate for Body {
+        fn validate(&self) -> Result<(), ApiError> {
+            Rules::new()
+                .check(
+                    "uid",
+                    matches!(self.uid, IdField::Given(uid) if (10000..=999_999_999_999).contains(&uid)),
+                    ApiError::IncorrectUidFormat,
+                )
+                .check(
+                    "password",
+                    self.password.len() >= 7,
+                    ApiError::IncorrectPasswordFormat,
+                )
+                .finish()
+        }
+    }
+
+    #[test]
+    fn wrong_types_do_not_panic() {
+        let body: Body = serde_json::from_str(r#"{"uid":"12345", "password":1234567}"#).unwrap();
+        assert_eq!(body.uid, IdField::Given(12345));
+        assert_eq!(body.password, "");
+        assert_eq!(body.login, "");
+
+        let body: Body = serde_json::from_str(r#"{"login":12345}"#).unwrap();
+        assert_eq!(body.login, "12345");
+        assert_eq!(body.uid, IdField::Missing);
+
+        // A uid sent must be one, it's not taken as left out.
+        for uid in [r#"[1]"#, r#""abc""#, "1.5"] {
+            let json = format!(r#"{{"uid":{}, "password":null}}"#, uid);
+            let body: Body = serde_json::from_str(&json).unwrap();
+            assert_eq!(body.uid, IdField::Invalid, "{}", uid);
+        }
+        let body: Body = serde_json::from_str(r#"{"uid":null}"#).unwrap();
+        assert_eq!(body.uid, IdField::Missing);
+    }
+
+    #[test]
+    fn every_field_is_reported() {
+        let body: Body = serde_json::from_str(r#"{"uid":1, "password":"a"}"#).unwrap();
+        let err = body.validate().unwrap_err();
+        assert_eq!(err.code(), 21);
+        assert_eq!(err.to_json()["data"].as_array().unwrap().len(), 2);
+        assert_eq!(err.to_json()["data"][1]["code"], 22);
+    }
+}
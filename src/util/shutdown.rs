@@ -0,0 +1,131 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_std::channel::{self, Receiver, Sender};
+use async_std::task;
+use tide::{Middleware, Next, Request, Response, StatusCode};
+
+// Coordinate the graceful shutdown: count the requests in flight,
+// and tell the background jobs to stop by closing a channel they all wait on.
+#[derive(Clone)]
+pub struct Shutdown {
+    in_flight: Arc<AtomicUsize>,
+    stopping: Arc<AtomicBool>,
+    sender: Arc<Mutex<Option<Sender<()>>>>,
+    receiver: Receiver<()>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (sender, receiver) = channel::bounded(1);
+        Self {
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            stopping: Arc::new(AtomicBool::new(false)),
+            sender: Arc::new(Mutex::new(Some(sender))),
+            receiver,
+        }
+    }
+
+    // Call the handler on SIGTERM or SIGINT.
+    pub fn on_signal(&self) -> Result<(), ctrlc::Error> {
+        let shutdown = self.clone();
+        ctrlc::set_handler(move || shutdown.trigger())
+    }
+
+    pub fn trigger(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        // Dropping the only sender closes the channel, which wakes up every waiter.
+        if let Ok(mut sender) = self.sender.lock() {
+            sender.take();
+        }
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+
+    // Resolve once the shutdown is triggered, background jobs race their work against it.
+    pub async fn wait(&self) {
+        while self.receiver.recv().await.is_ok() {}
+    }
+
+    // Wait for the requests in flight to finish, up to the deadline.
+    // Return whether every request has finished.
+    pub async fn drain(&self, deadline: Duration) -> bool {
+        let start = Instant::now();
+        while self.in_flight.load(Ordering::SeqCst) > 0 {
+            if start.elapsed() >= deadline {
+                return false;
+            }
+            task::sleep(Duration::from_millis(50)).await;
+        }
+        true
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Count every request in flight, and refuse the new ones once the shutdown is triggered.
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for Shutdown {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        if self.is_stopping() {
+            let mut res = Response::new(StatusCode::ServiceUnavailable);
+            res.insert_header("Connection", "close");
+            return Ok(res);
+        }
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        let res = next.run(req).await;
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use async_std::task;
+    use tide::http::{Method, Request, Response, Url};
+    use tide::StatusCode;
+
+    use crate::util::shutdown::Shutdown;
+
+    #[async_std::test]
+    async fn drain_waits_for_requests_in_flight() {
+        let shutdown = Shutdown::new();
+        let mut app = tide::new();
+        app.with(shutdown.clone());
+        app.at("/slow").get(|_| async {
+            task::sleep(Duration::from_millis(200)).await;
+            Ok("done")
+        });
+
+        let url = Url::parse("http://localhost/slow").unwrap();
+        let server = app.clone();
+        let in_flight = task::spawn(async move {
+            let res: Response = server
+                .respond(Request::new(Method::Get, url))
+                .await
+                .unwrap();
+            res.status()
+        });
+        task::sleep(Duration::from_millis(50)).await;
+
+        shutdown.trigger();
+        shutdown.wait().await;
+        assert!(!shutdown.drain(Duration::from_millis(10)).await);
+        assert!(shutdown.drain(Duration::from_secs(5)).await);
+        assert_eq!(in_flight.await, StatusCode::Ok);
+
+        // New requests are refused once the shutdown begins.
+        let url = Url::parse("http://localhost/slow").unwrap();
+        let res: Response = app.respond(Request::new(Method::Get, url)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::ServiceUnavailable);
+    }
+}
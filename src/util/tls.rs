@@ -0,0 +1,222 @@
+use std::fmt::{self, Debug, Display, Formatter};
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use async_std::net::{TcpListener, TcpStream};
+use async_std::prelude::*;
+use async_std::task;
+use futures_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use futures_rustls::server::TlsStream;
+use futures_rustls::TlsAcceptor;
+use tide::http::cookies::SameSite;
+use tide::http::Cookie;
+use tide::listener::{ListenInfo, Listener, ToListener};
+use tide::{log, Redirect, Request, Server};
+
+// Set on every request which came through `TlsListener`.
+#[derive(Clone, Copy, Debug)]
+pub struct Tls;
+
+// Build a cookie of the login state, it's Secure, HttpOnly and SameSite when the request came over tls.
+pub fn session_cookie<State>(req: &Request<State>, name: &str, value: String) -> Cookie<'static> {
+    let mut cookie = Cookie::new(name.to_string(), value);
+    if req.ext::<Tls>().is_some() {
+        cookie.set_secure(true);
+        cookie.set_http_only(true);
+        cookie.set_same_site(SameSite::Lax);
+    }
+    cookie
+}
+
+// Load the pem encoded certificate chain and the pkcs8 or rsa private key.
+pub fn load_tls_config(cert: &str, key: &str) -> io::Result<ServerConfig> {
+    let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert)?))?
+        .into_iter()
+        .map(Certificate)
+        .collect::<Vec<_>>();
+    let key = {
+        let mut reader = BufReader::new(File::open(key)?);
+        let mut keys = rustls_pemfile::pkcs8_private_keys(&mut reader)?;
+        if keys.is_empty() {
+            let mut reader = BufReader::new(File::open(key)?);
+            keys = rustls_pemfile::rsa_private_keys(&mut reader)?;
+        }
+        keys.into_iter()
+            .next()
+            .map(PrivateKey)
+            .ok_or_else(|| invalid(format!("{}: no private key found", key)))?
+    };
+
+    ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| invalid(e.to_string()))
+}
+
+// Answer every request on the plain port with a redirect to the same path over https.
+pub fn https_redirect(https_port: u16) -> Server<()> {
+    let mut app = tide::new();
+    app.at("*").all(move |req: Request<()>| async move {
+        let mut url = req.url().clone();
+        let host = req
+            .host()
+            .map(|it| it.split(':').next().unwrap_or(it).to_string())
+            .unwrap_or_else(|| "localhost".to_string());
+        // The url was built from a http request, so that setting these never fails.
+        let _ = url.set_scheme("https");
+        let _ = url.set_host(Some(&host));
+        let _ = url.set_port(Some(https_port));
+        Ok(Redirect::permanent(url.to_string()))
+    });
+    app
+}
+
+// A tide listener which terminates tls with rustls, the http part is left to async-h1 as tide does.
+pub struct TlsListener<State> {
+    addr: String,
+    acceptor: TlsAcceptor,
+    listener: Option<TcpListener>,
+    server: Option<Server<State>>,
+    info: Option<ListenInfo>,
+}
+
+impl<State> TlsListener<State> {
+    pub fn new(addr: &str, config: ServerConfig) -> Self {
+        Self {
+            addr: addr.to_string(),
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+            listener: None,
+            server: None,
+            info: None,
+        }
+    }
+}
+
+// async-h1 needs a cloneable stream, the tls stream is shared by the reading and the writing half.
+#[derive(Clone)]
+struct SharedStream(Arc<Mutex<TlsStream<TcpStream>>>);
+
+impl async_std::io::Read for SharedStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut stream = self.0.lock().map_err(|_| io::ErrorKind::Other)?;
+        Pin::new(&mut *stream).poll_read(cx, buf)
+    }
+}
+
+impl async_std::io::Write for SharedStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut stream = self.0.lock().map_err(|_| io::ErrorKind::Other)?;
+        Pin::new(&mut *stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut stream = self.0.lock().map_err(|_| io::ErrorKind::Other)?;
+        Pin::new(&mut *stream).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut stream = self.0.lock().map_err(|_| io::ErrorKind::Other)?;
+        Pin::new(&mut *stream).poll_close(cx)
+    }
+}
+
+fn handle_tls<State: Clone + Send + Sync + 'static>(
+    app: Server<State>,
+    acceptor: TlsAcceptor,
+    stream: TcpStream,
+) {
+    task::spawn(async move {
+        let local_addr = stream.local_addr().ok();
+        let peer_addr = stream.peer_addr().ok();
+        let stream = match acceptor.accept(stream).await {
+            Ok(stream) => SharedStream(Arc::new(Mutex::new(stream))),
+            Err(e) => {
+                log::debug!("tls handshake failed", { error: e.to_string() });
+                return;
+            }
+        };
+
+        let fut = async_h1::accept(stream, |mut req| async {
+            req.set_local_addr(local_addr);
+            req.set_peer_addr(peer_addr);
+            req.ext_mut().insert(Tls);
+            app.respond(req).await
+        });
+        if let Err(e) = fut.await {
+            log::error!("async-h1 error", { error: e.to_string() });
+        }
+    });
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Listener<State> for TlsListener<State> {
+    async fn bind(&mut self, server: Server<State>) -> io::Result<()> {
+        self.server = Some(server);
+        self.listener = Some(TcpListener::bind(&self.addr).await?);
+        self.info = Some(ListenInfo::new(self.to_string(), "tcp".to_string(), true));
+        Ok(())
+    }
+
+    async fn accept(&mut self) -> io::Result<()> {
+        let server = self
+            .server
+            .take()
+            .expect("`Listener::bind` must be called before `Listener::accept`");
+        let listener = self
+            .listener
+            .take()
+            .expect("`Listener::bind` must be called before `Listener::accept`");
+
+        let mut incoming = listener.incoming();
+        while let Some(stream) = incoming.next().await {
+            match stream {
+                Ok(stream) => handle_tls(server.clone(), self.acceptor.clone(), stream),
+                Err(e) => {
+                    log::error!("tls accept failed", { error: e.to_string() });
+                    task::sleep(std::time::Duration::from_millis(100)).await;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn info(&self) -> Vec<ListenInfo> {
+        self.info.iter().cloned().collect()
+    }
+}
+
+impl<State: Clone + Send + Sync + 'static> ToListener<State> for TlsListener<State> {
+    type Listener = Self;
+
+    fn to_listener(self) -> io::Result<Self::Listener> {
+        Ok(self)
+    }
+}
+
+impl<State> Debug for TlsListener<State> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsListener")
+            .field("addr", &self.addr)
+            .finish()
+    }
+}
+
+impl<State> Display for TlsListener<State> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "https://{}", self.addr)
+    }
+}
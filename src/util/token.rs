@@ -0,0 +1,82 @@
+use crypto::digest::Digest;
+use crypto::hmac::Hmac;
+use crypto::mac::{Mac, MacResult};
+use crypto::sha2::Sha256;
+use rand::RngCore;
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|ele| format!("{:02x}", ele)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+pub fn random_bytes(len: usize) -> Vec<u8> {
+    let mut data = vec![0u8; len];
+    rand::rngs::OsRng.fill_bytes(&mut data);
+    data
+}
+
+// A random hex string from the os rng, used for secrets and single-use tokens.
+pub fn random_token(bytes: usize) -> String {
+    to_hex(&random_bytes(bytes))
+}
+
+// Tokens are stored by their sha256, so that a leaked table can't be used to log in.
+pub fn hash_token(token: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input_str(token);
+    hasher.result_str()
+}
+
+// Hex encoded hmac-sha256 of the message.
+pub fn sign(secret: &str, message: &str) -> String {
+    let mut mac = Hmac::new(Sha256::new(), secret.as_bytes());
+    mac.input(message.as_bytes());
+    to_hex(mac.result().code())
+}
+
+// Compare the signature in constant time, so that it can't be guessed byte by byte.
+pub fn verify_signature(secret: &str, message: &str, signature: &str) -> bool {
+    let signature = match from_hex(signature) {
+        Some(signature) => signature,
+        None => return false,
+    };
+    let mut mac = Hmac::new(Sha256::new(), secret.as_bytes());
+    mac.input(message.as_bytes());
+    mac.result() == MacResult::new(&signature)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::util::token::{hash_token, random_token, sign, verify_signature};
+
+    #[test]
+    fn signature_round_trip() {
+        let signature = sign("secret", "12345|a@b.c");
+        assert_eq!(signature.len(), 64);
+        assert!(verify_signature("secret", "12345|a@b.c", &signature));
+        assert!(!verify_signature("other", "12345|a@b.c", &signature));
+        assert!(!verify_signature("secret", "12346|a@b.c", &signature));
+        for broken in ["", "zz", "abc", "１２"] {
+            assert!(!verify_signature("secret", "12345|a@b.c", broken));
+        }
+    }
+
+    #[test]
+    fn random_tokens_differ() {
+        assert_eq!(random_token(16).len(), 32);
+        assert_ne!(random_token(16), random_token(16));
+        assert_eq!(
+            hash_token("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
@@ -0,0 +1,143 @@
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha1::Sha1;
+
+const BASE32: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+// Seconds per code, and codes accepted before and after the current one for clock drift.
+const STEP: i64 = 30;
+const WINDOW: i64 = 1;
+
+// Rfc 4648 base32 without padding, the format authenticator apps read secrets in.
+pub fn base32_encode(data: &[u8]) -> String {
+    let mut text = String::new();
+    let (mut buffer, mut bits) = (0u32, 0);
+    for &byte in data {
+        buffer = (buffer << 8) | u32::from(byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            text.push(BASE32[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        text.push(BASE32[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    text
+}
+
+pub fn base32_decode(text: &str) -> Option<Vec<u8>> {
+    let mut data = Vec::new();
+    let (mut buffer, mut bits) = (0u32, 0);
+    for ch in text.trim_end_matches('=').bytes() {
+        let value = BASE32
+            .iter()
+            .position(|&it| it == ch.to_ascii_uppercase())? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            data.push((buffer >> bits) as u8);
+        }
+    }
+    Some(data)
+}
+
+// The 6 digits code of rfc 6238 for the time step.
+pub fn totp_code(secret: &[u8], step: i64) -> u32 {
+    let mut mac = Hmac::new(Sha1::new(), secret);
+    mac.input(&step.to_be_bytes());
+    let hash = mac.result();
+    let hash = hash.code();
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let binary = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    binary % 1_000_000
+}
+
+// The step the code matches around the time, only steps after `last_step` count so that a code is used once.
+pub fn verify_totp(secret: &[u8], code: &str, now: i64, last_step: i64) -> Option<i64> {
+    if code.len() != 6 || !code.bytes().all(|it| it.is_ascii_digit()) {
+        return None;
+    }
+    let code = code.parse::<u32>().ok()?;
+    let current = now / STEP;
+    (current - WINDOW..=current + WINDOW)
+        .filter(|step| *step > last_step)
+        .find(|step| totp_code(secret, *step) == code)
+}
+
+// The uri authenticator apps enroll from, usually shown as a qr code.
+pub fn provisioning_uri(issuer: &str, account: &str, secret: &str) -> String {
+    let issuer = encode_uri_component(issuer);
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits=6&period={}",
+        issuer,
+        encode_uri_component(account),
+        secret,
+        issuer,
+        STEP
+    )
+}
+
+fn encode_uri_component(text: &str) -> String {
+    text.bytes()
+        .map(|it| match it {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (it as char).to_string()
+            }
+            _ => format!("%{:02X}", it),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::util::totp::{
+        base32_decode, base32_encode, provisioning_uri, totp_code, verify_totp,
+    };
+
+    // The sha1 vectors of rfc 6238, cut to 6 digits.
+    #[test]
+    fn rfc_6238_vectors() {
+        let secret = b"12345678901234567890";
+        for (time, code) in [
+            (59, 287082),
+            (1111111109, 81804),
+            (1111111111, 50471),
+            (1234567890, 5924),
+            (2000000000, 279037),
+        ] {
+            assert_eq!(totp_code(secret, time / 30), code);
+        }
+    }
+
+    #[test]
+    fn code_is_accepted_once_around_now() {
+        let secret = b"12345678901234567890";
+        assert_eq!(verify_totp(secret, "287082", 59, 0), Some(1));
+        assert_eq!(verify_totp(secret, "287082", 80, 0), Some(1));
+        assert_eq!(verify_totp(secret, "287082", 59, 1), None);
+        assert_eq!(verify_totp(secret, "287082", 200, 0), None);
+        for broken in ["", "28708", "2870822", "28708a", "２８７０８２"] {
+            assert_eq!(verify_totp(secret, broken, 59, 0), None);
+        }
+    }
+
+    #[test]
+    fn base32_round_trip() {
+        assert_eq!(base32_encode(b"foobar"), "MZXW6YTBOI");
+        assert_eq!(base32_decode("MZXW6YTBOI======").unwrap(), b"foobar");
+        assert_eq!(base32_decode("mzxw6ytboi").unwrap(), b"foobar");
+        assert_eq!(base32_decode("MZ1"), None);
+        let data: Vec<u8> = (0..=255).collect();
+        assert_eq!(base32_decode(&base32_encode(&data)).unwrap(), data);
+        assert_eq!(
+            provisioning_uri("Finance App", "12345", "MZXW6YTBOI"),
+            "otpauth://totp/Finance%20App:12345?secret=MZXW6YTBOI&issuer=Finance%20App&algorithm=SHA1&digits=6&period=30"
+        );
+    }
+}
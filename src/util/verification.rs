@@ -0,0 +1,101 @@
+use chrono::Utc;
+use sqlx::{Acquire, MySql, Row};
+use tide::Request;
+use tide_sqlx::SQLxRequestExt;
+
+use super::api_error::ApiError;
+use super::mailer::{send_in_background, Mail};
+use super::token::{sign, verify_signature};
+use crate::state::State;
+
+// The signed part binds the email too, so that a token dies once the email is changed.
+fn signed_part(uid: i64, email: &str, expires: i64) -> String {
+    format!("verify-email|{}|{}|{}", uid, email, expires)
+}
+
+// The token is `uid.expires.signature`, nothing needs to be stored until it's used.
+pub fn verification_token(secret: &str, uid: i64, email: &str, expires: i64) -> String {
+    let signature = sign(secret, &signed_part(uid, email, expires));
+    format!("{}.{}.{}", uid, expires, signature)
+}
+
+// The uid a token claims, it's still to be checked against the email of that user.
+pub fn token_uid(token: &str) -> Option<i64> {
+    token.split('.').next()?.parse().ok()
+}
+
+pub fn check_verification_token(secret: &str, token: &str, email: &str, now: i64) -> bool {
+    let mut parts = token.split('.');
+    let (uid, expires, signature) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(uid), Some(expires), Some(signature), None) => (uid, expires, signature),
+        _ => return false,
+    };
+    let (uid, expires) = match (uid.parse::<i64>(), expires.parse::<i64>()) {
+        (Ok(uid), Ok(expires)) => (uid, expires),
+        _ => return false,
+    };
+    expires > now && verify_signature(secret, &signed_part(uid, email, expires), signature)
+}
+
+// Mail the verification link of the user.
+pub fn send_verification(state: &State, uid: i64, email: &str) {
+    let config = &state.config.verification;
+    let expires = Utc::now().timestamp() + config.ttl_secs;
+    let token = verification_token(&config.secret, uid, email, expires);
+    let link = config.link.replace("{token}", &token);
+    let mail = Mail {
+        to: email.to_string(),
+        subject: "Verify your email".to_string(),
+        body: format!(
+            "Open the link below to verify the email of your finance account {}.\n\n{}\n\nThe link expires in {} hours.",
+            uid,
+            link,
+            config.ttl_secs / 3600
+        ),
+    };
+    send_in_background(state.mailer.clone(), mail);
+}
+
+// Refuse the action if the config restricts it to verified accounts and the user isn't one.
+pub async fn require_verified(
+    req: &Request<State>,
+    uid: i64,
+    action: &str,
+) -> Result<(), ApiError> {
+    let restrict = &req.state().config.verification.restrict;
+    if !restrict.iter().any(|it| it == action) {
+        return Ok(());
+    }
+    let mut conn = req.sqlx_conn::<MySql>().await;
+    let verified = sqlx::query("select verified from user where uid=?")
+        .bind(uid)
+        .fetch_optional(conn.acquire().await?)
+        .await?
+        .is_some_and(|row| row.get::<bool, &str>("verified"));
+    if verified {
+        Ok(())
+    } else {
+        Err(ApiError::EmailNotVerified)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::util::verification::{check_verification_token, token_uid, verification_token};
+
+    #[test]
+    fn token_is_bound_to_email_and_expiry() {
+        let token = verification_token("secret", 12345, "a@b.c", 100);
+        assert_eq!(token_uid(&token), Some(12345));
+        assert!(check_verification_token("secret", &token, "a@b.c", 99));
+        assert!(!check_verification_token("secret", &token, "a@b.c", 100));
+        assert!(!check_verification_token("secret", &token, "x@b.c", 99));
+        assert!(!check_verification_token("other", &token, "a@b.c", 99));
+
+        let forged = token.replacen("100", "999", 1);
+        assert!(!check_verification_token("secret", &forged, "a@b.c", 99));
+        for broken in ["", "12345", "12345.100", "a.b.c", &format!("{}.x", token)] {
+            assert!(!check_verification_token("secret", broken, "a@b.c", 99));
+        }
+    }
+}